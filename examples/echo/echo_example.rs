@@ -1,4 +1,3 @@
-use std::convert::Infallible;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
@@ -6,21 +5,18 @@ use http::Request;
 use http_body_util::combinators::UnsyncBoxBody;
 use hyper::body::Bytes;
 use hyper::Response;
-use log::{debug, info};
+use log::info;
 use hyper_line::server::{HttpMethod, PathConfig};
-use hyper_line::handler::{Handler, HandlerId};
-use hyper_line::handler::reverse_proxy_handler::{ProxyConfig, ReverseProxyHandler};
+use hyper_line::handler::Handler;
 use hyper_line::server::ServerBuilder;
-use rustls::ServerConfig as TlsServerConfig;
-use hyper_line::cert_manager;
-use hyper_line::{HttpRequest, HttpResponse};
+use hyper_line::{BoxError, HttpRequest, HttpResponse};
 
 #[derive(Default)]
 struct ExampleEchoHandler;
 impl Handler<HttpRequest, HttpResponse> for ExampleEchoHandler {
     fn process<'i1, 'i2, 'o>(
         &'i1 self,
-        context: &'i2 mut hyper_line::exchange::Exchange<Request<UnsyncBoxBody<Bytes, Infallible>>, Response<UnsyncBoxBody<Bytes, Infallible>>>
+        context: &'i2 mut hyper_line::exchange::Exchange<Request<UnsyncBoxBody<Bytes, BoxError>>, Response<UnsyncBoxBody<Bytes, BoxError>>>
     ) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send + 'o>>
     where
         'i1: 'o,
@@ -41,7 +37,7 @@ impl Handler<HttpRequest, HttpResponse> for ExampleEchoHandler {
 fn main() {
     hyper_line::logger::setup_logger();
 
-    hyper_line::handler::register("EchoHandler", Arc::new(ExampleEchoHandler::default()));
+    hyper_line::handler::register("EchoHandler", Arc::new(ExampleEchoHandler));
 
     let mut builder = ServerBuilder::new();
     builder
@@ -4,6 +4,17 @@ use hyper_line::server::{HttpMethod, PathConfig, ServerBuilder};
 
 fn main() {
     hyper_line::logger::setup_logger();
+    let proxy = match ReverseProxyHandler::try_new(ProxyConfig {
+        destination_port: 8081,
+        destination_host: "127.0.0.1".to_string(),
+        ..ProxyConfig::default()
+    }) {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            eprintln!("FAILED: {:?}", e);
+            std::process::exit(1);
+        }
+    };
     let mut builder = ServerBuilder::new();
     builder
         .worker_thread_name("WT")
@@ -12,10 +23,7 @@ fn main() {
         .add_path(PathConfig {
             path: "/test".to_string(),
             method: HttpMethod::Post,
-            request: vec![Arc::new(ReverseProxyHandler::new(ProxyConfig {
-                destination_port: 8081,
-                destination_host: "127.0.0.1".to_string(),
-            }))],
+            request: vec![Arc::new(proxy)],
             response: vec![],
         });
 
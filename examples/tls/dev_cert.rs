@@ -0,0 +1,38 @@
+use std::path::PathBuf;
+use hyper_line::cert_manager;
+
+/// Generates a development certificate for local HTTPS:
+///
+/// `cargo run --example dev_cert -- [--ca] [--dir DIR] [HOSTNAME...]`
+fn main() {
+    hyper_line::logger::setup_logger();
+
+    let mut dir = PathBuf::from(".dev-certs");
+    let mut with_ca = false;
+    let mut hostnames = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--ca" => with_ca = true,
+            "--dir" => dir = args.next().expect("--dir needs a directory").into(),
+            _ => hostnames.push(arg),
+        }
+    }
+    if hostnames.is_empty() {
+        hostnames = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+    }
+
+    match cert_manager::dev_certificate(&dir, &hostnames, with_ca) {
+        Ok(files) => {
+            println!("certificate: {}", files.cert_file.display());
+            println!("key:         {}", files.key_file.display());
+            if let Some(ca_file) = files.ca_file {
+                println!("trust CA:    {}", ca_file.display());
+            }
+        }
+        Err(e) => {
+            eprintln!("FAILED: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
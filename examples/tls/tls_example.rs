@@ -5,10 +5,11 @@ use hyper::Response;
 use log::info;
 use hyper_line::server::{HttpMethod, PathConfig, ServerBuilder};
 use hyper_line::handler::Handler;
-use hyper_line::handler::reverse_proxy_handler::{ProxyConfig, ReverseProxyHandler};
-use rustls::ServerConfig as TlsServerConfig;
-use hyper_line::{cert_manager, HttpRequest, HttpResponse};
-use hyper_line::exchange::Exchange;
+use hyper_line::handler::cert_expiry_handler::{CertExpiryHandler, CertExpiryMetricsHandler};
+use hyper_line::tls_config::TlsConfig;
+use hyper_line::{HttpRequest, HttpResponse};
+use hyper_line::cert_manager::PeerIdentity;
+use hyper_line::exchange::{AttachmentKey, Exchange};
 
 struct ExampleEchoHandler;
 impl Handler<HttpRequest, HttpResponse> for ExampleEchoHandler {
@@ -23,6 +24,9 @@ impl Handler<HttpRequest, HttpResponse> for ExampleEchoHandler {
     {
         Box::pin(async move {
             info!("Echo handler");
+            if let Some(identity) = context.attachment::<Arc<PeerIdentity>>(AttachmentKey::PEER_IDENTITY) {
+                info!("Client certificate {} ({})", identity.subject, identity.fingerprint);
+            }
             let consumed = context.consume_request().unwrap();
             let (_, request) = consumed.into_parts();
             let echoed_response = Response::new(request);
@@ -35,25 +39,34 @@ impl Handler<HttpRequest, HttpResponse> for ExampleEchoHandler {
 fn main() {
     hyper_line::logger::setup_logger();
 
-    let private_key = cert_manager::load_private_key("./examples/tls/server.rsa").unwrap();
-    let public_key = cert_manager::load_certs("./examples/tls/server.pem").unwrap();
-
-    let tls_server_config = rustls::ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(public_key, private_key)
-        .unwrap();
+    /* certificates, protocol versions, ciphers and ALPN come from the tls section */
+    let content = std::fs::read_to_string("./examples/tls/tls.json").unwrap();
+    let tls_config: TlsConfig = serde_json::from_str(&content).unwrap();
 
     let mut builder = ServerBuilder::new();
     builder
         .worker_thread_name("WT")
         .worker_threads(1)
         .port(8081)
-        .tls_server_config(tls_server_config)
+        .tls(&tls_config)
+        .unwrap()
         .add_path(PathConfig {
             path: "/test".to_string(),
             method: HttpMethod::Post,
             request: vec![Arc::new(ExampleEchoHandler{})],
             response: vec![],
+        })
+        .add_path(PathConfig {
+            path: "/admin/certificates".to_string(),
+            method: HttpMethod::Get,
+            request: vec![Arc::new(CertExpiryHandler)],
+            response: vec![],
+        })
+        .add_path(PathConfig {
+            path: "/admin/metrics".to_string(),
+            method: HttpMethod::Get,
+            request: vec![Arc::new(CertExpiryMetricsHandler)],
+            response: vec![],
         });
 
     if let Err(e) = hyper_line::server::run_server(builder.build()) {
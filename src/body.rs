@@ -0,0 +1,158 @@
+use std::fmt::Display;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use hyper::HeaderMap;
+use log::warn;
+use http_body_util::{BodyExt, Empty, Full};
+use crate::{BoxError, HttpBody};
+
+/// Passes a body such as `Incoming` on without buffering it, ending it with `error_trailers`
+/// when it fails. That is how gRPC reports a call failing midway, other bodies should pass the
+/// error on so that the peer sees a reset stream.
+pub struct ErrorTrailersBody<B> {
+    inner: B,
+    error_trailers: Option<HeaderMap>,
+    done: bool,
+}
+
+impl<B> ErrorTrailersBody<B> {
+    pub fn new(inner: B, error_trailers: HeaderMap) -> Self {
+        Self {
+            inner,
+            error_trailers: Some(error_trailers),
+            done: false,
+        }
+    }
+}
+
+impl<B> Body for ErrorTrailersBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Display,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => Poll::Ready(Some(Ok(frame))),
+            Poll::Ready(Some(Err(e))) => {
+                warn!("Body stream failed: {}", e);
+                self.done = true;
+                Poll::Ready(self.error_trailers.take().map(|trailers| Ok(Frame::trailers(trailers))))
+            }
+            Poll::Ready(None) => {
+                self.done = true;
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done || self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// A body sending `data` in one frame.
+pub fn full(data: impl Into<Bytes>) -> HttpBody {
+    Full::new(data.into()).map_err(BoxError::from).boxed_unsync()
+}
+
+pub fn empty() -> HttpBody {
+    Empty::<Bytes>::new().map_err(BoxError::from).boxed_unsync()
+}
+
+/// Outcome of `buffer_limited`.
+pub enum Buffered {
+    Complete(Bytes),
+    /// The body was larger than the limit. It still holds all of its data, the part that was
+    /// read followed by the rest of the stream.
+    TooLarge(HttpBody),
+}
+
+/// Reads `body` into memory unless it turns out to be larger than `limit` bytes, in which case
+/// reading stops and the body is handed back to be streamed. Trailers of a complete body are
+/// dropped.
+pub async fn buffer_limited(mut body: HttpBody, limit: usize) -> Result<Buffered, BoxError> {
+    if body.size_hint().lower() > limit as u64 {
+        return Ok(Buffered::TooLarge(body));
+    }
+    let mut buffer = Vec::new();
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame?.into_data() {
+            buffer.extend_from_slice(&data);
+        }
+        if buffer.len() > limit {
+            let prefixed = PrefixedBody { prefix: Some(Bytes::from(buffer)), inner: body };
+            return Ok(Buffered::TooLarge(prefixed.boxed_unsync()));
+        }
+    }
+    Ok(Buffered::Complete(Bytes::from(buffer)))
+}
+
+/* data already read from `inner`, sent before the rest of it */
+struct PrefixedBody {
+    prefix: Option<Bytes>,
+    inner: HttpBody,
+}
+
+impl Body for PrefixedBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Some(prefix) = self.prefix.take() {
+            return Poll::Ready(Some(Ok(Frame::data(prefix))));
+        }
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.prefix.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let prefix = self.prefix.as_ref().map_or(0, |prefix| prefix.len() as u64);
+        let inner = self.inner.size_hint();
+        let mut hint = SizeHint::new();
+        hint.set_lower(inner.lower() + prefix);
+        if let Some(upper) = inner.upper() {
+            hint.set_upper(upper + prefix);
+        }
+        hint
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_large_body_is_handed_back_whole() {
+        /* two data frames, without an exact size hint */
+        let body = || PrefixedBody { prefix: Some(Bytes::from_static(b"abc")), inner: full("def") }.boxed_unsync();
+        match buffer_limited(body(), 6).await.unwrap() {
+            Buffered::Complete(data) => assert_eq!(data, "abcdef"),
+            Buffered::TooLarge(_) => panic!("Should buffer a body within the limit."),
+        }
+        match buffer_limited(body(), 2).await.unwrap() {
+            Buffered::Complete(_) => panic!("Should not buffer a body above the limit."),
+            Buffered::TooLarge(rest) => assert_eq!(rest.collect().await.unwrap().to_bytes(), "abcdef"),
+        }
+    }
+}
@@ -1,13 +1,44 @@
+use std::collections::HashMap;
 use std::{fs, io};
-use std::path::PathBuf;
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{debug, error, info, warn};
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, Issuer, KeyPair, KeyUsagePurpose};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use serde::Serialize;
+use x509_parser::extensions::GeneralName;
+use x509_parser::time::ASN1Time;
+use crate::server::record_unknown_sni;
 
-#[derive(Default)]
+mod ocsp;
+
+/// Certificates and keys of the server. The n-th certificate file is paired with the n-th key
+/// file, a combined PEM bundle holding both can be added with `add_bundle`. Files may be PEM,
+/// with any number of certificates and keys, or DER with a single one.
+///
+/// A DER OCSP response in `<cert file>.ocsp` is stapled to the handshake as long as it reports
+/// the certificate as good and has not expired.
+#[derive(Debug, Clone, Default)]
 pub struct KeyManager {
     certs_files: Vec<PathBuf>,
     keys_files: Vec<PathBuf>
 }
 
+/// A certificate chain with its key, checked to belong together.
+pub struct LoadedCert {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    pub certified_key: Arc<CertifiedKey>,
+    /// Unix time the stapled OCSP response expires at, if any.
+    pub ocsp_next_update: Option<i64>,
+}
+
 impl KeyManager {
     pub fn add_cert(&mut self, path: PathBuf) {
         self.certs_files.push(path);
@@ -17,37 +48,851 @@ impl KeyManager {
         self.keys_files.push(path);
     }
 
+    /// Adds a PEM file holding a certificate chain and its private key.
+    pub fn add_bundle(&mut self, path: PathBuf) {
+        self.certs_files.push(path.clone());
+        self.keys_files.push(path);
+    }
+
+    /// Certificates of every certificate file, in the order they were added.
     pub fn load_certs(&self) -> io::Result<Vec<CertificateDer<'static>>> {
-        todo!()
+        let mut certs = vec![];
+        for path in &self.certs_files {
+            certs.extend(read_certs(path)?);
+        }
+        if certs.is_empty() {
+            return Err(error("no certificate files configured".to_string()));
+        }
+        Ok(certs)
     }
 
+    /// Key of the first key file.
     pub fn load_keys(&self) -> io::Result<PrivateKeyDer<'static>> {
-        todo!()
+        match self.keys_files.first() {
+            Some(path) => read_private_key(path),
+            None => Err(error("no key files configured".to_string())),
+        }
+    }
+
+    /// Loads every certificate chain with its key, failing when a key does not match the
+    /// public key of its certificate.
+    pub fn load(&self) -> io::Result<Vec<LoadedCert>> {
+        if self.certs_files.len() != self.keys_files.len() {
+            return Err(error(format!(
+                "{} certificate files but {} key files, each certificate needs its key",
+                self.certs_files.len(),
+                self.keys_files.len()
+            )));
+        }
+        self.certs_files
+            .iter()
+            .zip(&self.keys_files)
+            .map(|(cert_file, key_file)| {
+                let mut certified_key = load_certified_key(cert_file, key_file)?;
+                let ocsp_next_update = ocsp::staple(&mut certified_key, cert_file, unix_now());
+                Ok(LoadedCert {
+                    cert_file: cert_file.clone(),
+                    key_file: key_file.clone(),
+                    certified_key: Arc::new(certified_key),
+                    ocsp_next_update,
+                })
+            })
+            .collect()
+    }
+
+    fn files(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.certs_files
+            .iter()
+            .chain(&self.keys_files)
+            .cloned()
+            .chain(self.certs_files.iter().map(|path| ocsp::response_file(path)))
+    }
+}
+
+/// Picks the certificate for a TLS connection by the server name the client sent (SNI). An
+/// exact name wins over a wildcard, clients sending no or an unknown name get the default.
+#[derive(Debug, Default)]
+pub struct SniResolver {
+    exact: HashMap<String, Arc<CertifiedKey>>,
+    wildcard: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+    expiry: Vec<CertExpiry>,
+    ocsp_next_update: Option<i64>,
+}
+
+/// Validity of a served certificate.
+#[derive(Debug, Clone, Serialize)]
+pub struct CertExpiry {
+    pub cert_file: PathBuf,
+    pub names: Vec<String>,
+    /// Unix time the certificate expires at.
+    pub not_after: i64,
+    /// Whole days left, negative once expired.
+    pub days_until_expiry: i64,
+}
+
+impl CertExpiry {
+    pub fn expired(&self) -> bool {
+        self.not_after <= unix_now()
+    }
+}
+
+impl SniResolver {
+    /// Serves the certificates of `key_manager`, the first one is the default.
+    pub fn from_key_manager(key_manager: &KeyManager) -> io::Result<Self> {
+        let mut resolver = Self::default();
+        let certificates = key_manager.load()?;
+        if certificates.is_empty() {
+            return Err(error("no certificate files configured".to_string()));
+        }
+        for loaded in certificates {
+            let expires = not_after(leaf(&loaded.certified_key)?)?;
+            let names = resolver.add(loaded.certified_key)?;
+            info!(
+                "Serving certificate {} for {}, valid until {}",
+                loaded.cert_file.display(),
+                names.join(", "),
+                expires
+            );
+            resolver.ocsp_next_update = match (resolver.ocsp_next_update, loaded.ocsp_next_update) {
+                (Some(current), Some(next)) => Some(current.min(next)),
+                (current, next) => current.or(next),
+            };
+            resolver.expiry.push(CertExpiry {
+                cert_file: loaded.cert_file,
+                names,
+                not_after: expires.timestamp(),
+                days_until_expiry: 0,
+            });
+        }
+        Ok(resolver)
+    }
+
+    /// Validity of the certificates loaded from files, as of now.
+    pub fn expiry(&self) -> Vec<CertExpiry> {
+        let now = unix_now();
+        self.expiry
+            .iter()
+            .map(|cert| CertExpiry {
+                days_until_expiry: (cert.not_after - now).div_euclid(86400),
+                ..cert.clone()
+            })
+            .collect()
+    }
+
+    /// Serves `key` for the DNS names of its certificate, returning those names. The first key
+    /// added becomes the default.
+    pub fn add(&mut self, key: Arc<CertifiedKey>) -> io::Result<Vec<String>> {
+        let names = certificate_names(leaf(&key)?)?;
+        for name in &names {
+            match name.strip_prefix("*.") {
+                Some(parent) => self.wildcard.insert(parent.to_string(), key.clone()),
+                None => self.exact.insert(name.clone(), key.clone()),
+            };
+        }
+        if self.default.is_none() {
+            self.default = Some(key);
+        }
+        Ok(names)
+    }
+
+    pub fn set_default(&mut self, key: Arc<CertifiedKey>) {
+        self.default = Some(key);
+    }
+
+    /// Certificate for `server_name`, matching wildcards only one label deep like browsers do.
+    pub fn lookup(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let Some(name) = server_name else {
+            return self.default.clone();
+        };
+        self.find(name).or_else(|| {
+            debug!("No certificate for server name {}, using the default", name);
+            self.default.clone()
+        })
+    }
+
+    /// Certificate issued for `server_name`, without falling back to the default.
+    pub fn find(&self, server_name: &str) -> Option<Arc<CertifiedKey>> {
+        let name = server_name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(key) = self.exact.get(&name) {
+            return Some(key.clone());
+        }
+        name.split_once('.')
+            .and_then(|(_, parent)| self.wildcard.get(parent))
+            .cloned()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let key = self.lookup(client_hello.server_name());
+        if key.is_none() {
+            record_unknown_sni();
+        }
+        key
+    }
+}
+
+/// Serves the certificates of a `KeyManager` and replaces them when their files change or
+/// `reload` is called. A reload only affects new handshakes, established connections keep the
+/// certificate they were opened with. A reload failing to load, e.g. because a key does not
+/// match its certificate, is rejected and the current certificates stay in use.
+#[derive(Debug)]
+pub struct CertReloader {
+    key_manager: KeyManager,
+    current: RwLock<Arc<SniResolver>>,
+    modified: Mutex<Vec<Option<SystemTime>>>,
+    strict_sni: AtomicBool,
+    watching: AtomicBool,
+    monitoring_expiry: AtomicBool,
+}
+
+impl CertReloader {
+    pub fn new(key_manager: KeyManager) -> io::Result<Arc<Self>> {
+        let modified = modification_times(&key_manager);
+        let resolver = SniResolver::from_key_manager(&key_manager)?;
+        Ok(Arc::new(Self {
+            key_manager,
+            current: RwLock::new(Arc::new(resolver)),
+            modified: Mutex::new(modified),
+            strict_sni: AtomicBool::new(false),
+            watching: AtomicBool::new(false),
+            monitoring_expiry: AtomicBool::new(false),
+        }))
+    }
+
+    /// Fails handshakes naming a server none of the certificates is issued for, instead of
+    /// serving the default certificate. Clients sending no name still get the default.
+    pub fn set_strict_sni(&self, strict: bool) {
+        self.strict_sni.store(strict, Ordering::Relaxed);
     }
+
+    /// Loads the certificate files again and swaps them in if they are all valid.
+    pub fn reload(&self) -> io::Result<()> {
+        let modified = modification_times(&self.key_manager);
+        let result = SniResolver::from_key_manager(&self.key_manager);
+        /* remembered on failure as well, a broken file is reported once and not on every poll */
+        *self.modified.lock().unwrap() = modified;
+        match result {
+            Ok(resolver) => {
+                *self.current.write().unwrap() = Arc::new(resolver);
+                info!("TLS certificates reloaded");
+                Ok(())
+            }
+            Err(e) => {
+                error!("TLS certificate reload rejected, keeping the current certificates: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Checks the certificate, key and OCSP response files for changes every `interval`,
+    /// reloading when one changed or a stapled OCSP response expired. The watch ends when the
+    /// reloader is dropped.
+    pub fn watch(self: &Arc<Self>, interval: Duration) {
+        if self.watching.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let reloader: Weak<Self> = Arc::downgrade(self);
+        std::thread::Builder::new()
+            .name("cert-watch".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(interval);
+                let Some(reloader) = reloader.upgrade() else { break };
+                let changed = *reloader.modified.lock().unwrap() != modification_times(&reloader.key_manager);
+                let ocsp_expired = reloader.current().ocsp_next_update.is_some_and(|next| next <= unix_now());
+                if changed || ocsp_expired {
+                    let _ = reloader.reload();
+                }
+            })
+            .expect("failed to spawn the certificate watch thread");
+    }
+
+    pub fn current(&self) -> Arc<SniResolver> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Days until each served certificate expires.
+    pub fn expiry(&self) -> Vec<CertExpiry> {
+        self.current().expiry()
+    }
+
+    /// The days until expiry of the served certificates as a gauge in the Prometheus text format,
+    /// labelled with the certificate file and its names.
+    pub fn expiry_metrics(&self) -> String {
+        let mut metrics = String::from(concat!(
+            "# HELP tls_certificate_days_until_expiry Whole days until the certificate expires, negative once expired.\n",
+            "# TYPE tls_certificate_days_until_expiry gauge\n",
+        ));
+        for cert in self.expiry() {
+            metrics.push_str(&format!(
+                "tls_certificate_days_until_expiry{{cert_file=\"{}\",names=\"{}\"}} {}\n",
+                label_value(&cert.cert_file.to_string_lossy()),
+                label_value(&cert.names.join(",")),
+                cert.days_until_expiry
+            ));
+        }
+        metrics
+    }
+
+    /// Logs an error for every expired certificate and a warning for those expiring within
+    /// `window`, returning the expiry of all of them.
+    pub fn check_expiry(&self, window: Duration) -> Vec<CertExpiry> {
+        let expiry = self.expiry();
+        let warn_after = unix_now() + window.as_secs() as i64;
+        for cert in &expiry {
+            if cert.expired() {
+                error!("Certificate {} for {} has expired", cert.cert_file.display(), cert.names.join(", "));
+            } else if cert.not_after <= warn_after {
+                warn!(
+                    "Certificate {} for {} expires in {} days",
+                    cert.cert_file.display(),
+                    cert.names.join(", "),
+                    cert.days_until_expiry
+                );
+            }
+        }
+        expiry
+    }
+
+    /// Runs `check_expiry` every `interval` until the reloader is dropped. Only the first call
+    /// starts monitoring, as does only the first call of `watch`.
+    pub fn monitor_expiry(self: &Arc<Self>, window: Duration, interval: Duration) {
+        if self.monitoring_expiry.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let reloader: Weak<Self> = Arc::downgrade(self);
+        std::thread::Builder::new()
+            .name("cert-expiry".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(interval);
+                let Some(reloader) = reloader.upgrade() else { break };
+                reloader.check_expiry(window);
+            })
+            .expect("failed to spawn the certificate expiry thread");
+    }
+}
+
+impl ResolvesServerCert for CertReloader {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let resolver = self.current();
+        let key = match client_hello.server_name() {
+            Some(name) if self.strict_sni.load(Ordering::Relaxed) => resolver.find(name),
+            server_name => resolver.lookup(server_name),
+        };
+        if key.is_none() {
+            record_unknown_sni();
+        }
+        key
+    }
+}
+
+/* escapes a Prometheus label value */
+fn label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn modification_times(key_manager: &KeyManager) -> Vec<Option<SystemTime>> {
+    key_manager
+        .files()
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+fn leaf(key: &CertifiedKey) -> io::Result<&CertificateDer<'_>> {
+    key.end_entity_cert().map_err(|e| error(e.to_string()))
+}
+
+fn not_after(cert: &CertificateDer<'_>) -> io::Result<ASN1Time> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert)
+        .map_err(|e| invalid(format!("cannot parse certificate: {}", e)))?;
+    Ok(parsed.validity().not_after)
+}
+
+/// Identity of a client that authenticated with a certificate, attached to the exchange as
+/// `AttachmentKey::PEER_IDENTITY`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// Distinguished name, e.g. `CN=billing, O=Example`.
+    pub subject: String,
+    /// Subject alternative names: DNS names, email addresses, URIs and IP addresses.
+    pub sans: Vec<String>,
+    /// Hex encoded SHA-256 of the DER certificate.
+    pub fingerprint: String,
+}
+
+impl PeerIdentity {
+    pub fn from_certificate(cert: &CertificateDer<'_>) -> io::Result<Self> {
+        let (_, parsed) = x509_parser::parse_x509_certificate(cert)
+            .map_err(|e| invalid(format!("cannot parse certificate: {}", e)))?;
+        let sans = match parsed.subject_alternative_name() {
+            Ok(Some(san)) => san
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(value) | GeneralName::RFC822Name(value) | GeneralName::URI(value) => {
+                        Some(value.to_string())
+                    }
+                    GeneralName::IPAddress(bytes) => ip_address(bytes),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        };
+        let digest = aws_lc_rs::digest::digest(&aws_lc_rs::digest::SHA256, cert);
+        Ok(Self {
+            subject: parsed.subject().to_string(),
+            sans,
+            fingerprint: digest.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect(),
+        })
+    }
+}
+
+fn ip_address(bytes: &[u8]) -> Option<String> {
+    match bytes.len() {
+        4 => Some(std::net::Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?).to_string()),
+        16 => Some(std::net::Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?).to_string()),
+        _ => None,
+    }
+}
+
+/// DNS names a certificate is valid for, from its subject alternative names or else its common name.
+pub fn certificate_names(cert: &CertificateDer<'_>) -> io::Result<Vec<String>> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert)
+        .map_err(|e| invalid(format!("cannot parse certificate: {}", e)))?;
+    let mut names: Vec<String> = match parsed.subject_alternative_name() {
+        Ok(Some(san)) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(dns) => Some(dns.to_ascii_lowercase()),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    };
+    if names.is_empty() {
+        names.extend(
+            parsed
+                .subject()
+                .iter_common_name()
+                .filter_map(|cn| cn.as_str().ok())
+                .map(str::to_ascii_lowercase),
+        );
+    }
+    Ok(names)
+}
+
+/// Loads a certificate chain and its key, checking that the key matches the end-entity certificate.
+pub fn load_certified_key(cert_file: &Path, key_file: &Path) -> io::Result<CertifiedKey> {
+    let chain = read_certs(cert_file)?;
+    let key = read_private_key(key_file)?;
+    let provider = CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()));
+    CertifiedKey::from_der(chain, key, &provider).map_err(|e| {
+        error(format!(
+            "key {} does not fit certificate {}: {}",
+            key_file.display(),
+            cert_file.display(),
+            e
+        ))
+    })
 }
 
 fn error(err: String) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, err)
+    io::Error::other(err)
 }
 
-pub fn load_certs(filename: &str) -> io::Result<Vec<CertificateDer<'static>>> {
-    let public_cert = fs::File::open(filename)
-        .map_err(|e| {
-            error(format!("failed to open {}: {}", filename, e))
-        })?;
-    let mut reader = io::BufReader::new(public_cert);
+fn invalid(err: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("failed to open {}: {}", path.display(), e)))
+}
+
+fn is_pem(content: &[u8]) -> bool {
+    content.windows(11).any(|window| window == b"-----BEGIN ")
+}
+
+fn read_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let content = read_file(path)?;
+    let certs = if is_pem(&content) {
+        rustls_pemfile::certs(&mut content.as_slice())
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(|e| invalid(format!("invalid PEM in {}: {}", path.display(), e)))?
+    } else {
+        vec![CertificateDer::from(content)]
+    };
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificate found in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn read_private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let content = read_file(path)?;
+    if is_pem(&content) {
+        rustls_pemfile::private_key(&mut content.as_slice())
+            .map_err(|e| invalid(format!("invalid PEM in {}: {}", path.display(), e)))?
+            .ok_or_else(|| invalid(format!("no PKCS#1, PKCS#8 or SEC1 private key found in {}", path.display())))
+    } else {
+        PrivateKeyDer::try_from(content)
+            .map_err(|e| invalid(format!("no private key in DER file {}: {}", path.display(), e)))
+    }
+}
+
+/// Files of a development certificate made by `dev_certificate`.
+#[derive(Debug, Clone)]
+pub struct DevCertificate {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// Certificate of the local CA that signed the certificate, to be added to the trust store
+    /// of browsers and clients. Not set for a self-signed certificate.
+    pub ca_file: Option<PathBuf>,
+}
+
+const DEV_CERT_DAYS: i64 = 365;
+const DEV_CA_DAYS: i64 = 10 * 365;
+
+/// Makes a certificate for local HTTPS covering `hostnames`, which may be DNS names or IP
+/// addresses, and writes it to `dir`. With `with_ca` it is signed by a local CA created in
+/// `dir` as well, so clients only need to trust that CA once. Files from an earlier call are
+/// reused while they cover the hostnames and are not about to expire. Never use these in
+/// production.
+pub fn dev_certificate(dir: &Path, hostnames: &[String], with_ca: bool) -> io::Result<DevCertificate> {
+    fs::create_dir_all(dir).map_err(|e| io::Error::new(e.kind(), format!("cannot create {}: {}", dir.display(), e)))?;
+    let files = DevCertificate {
+        cert_file: dir.join("dev-cert.pem"),
+        key_file: dir.join("dev-key.pem"),
+        ca_file: with_ca.then(|| dir.join("dev-ca.pem")),
+    };
+    let ca = match &files.ca_file {
+        Some(ca_file) => Some(dev_ca(ca_file, &dir.join("dev-ca-key.pem"))?),
+        None => None,
+    };
+    if dev_certificate_usable(&files, hostnames) {
+        debug!("Reusing development certificate {}", files.cert_file.display());
+        return Ok(files);
+    }
+
+    let mut params = CertificateParams::new(hostnames.to_vec()).map_err(generate_error)?;
+    params.distinguished_name.push(DnType::CommonName, hostnames.first().map(String::as_str).unwrap_or("localhost"));
+    params.not_after = valid_for_days(DEV_CERT_DAYS)?.to_datetime();
+    let key = KeyPair::generate().map_err(generate_error)?;
+    let cert = match &ca {
+        Some(issuer) => params.signed_by(&key, issuer),
+        None => params.self_signed(&key),
+    }
+    .map_err(generate_error)?;
+    let chain = match &files.ca_file {
+        Some(ca_file) => format!("{}{}", cert.pem(), String::from_utf8_lossy(&read_file(ca_file)?)),
+        None => cert.pem(),
+    };
+    write_file(&files.cert_file, chain.as_bytes(), false)?;
+    write_file(&files.key_file, key.serialize_pem().as_bytes(), true)?;
+    info!("Generated development certificate {} for {}", files.cert_file.display(), hostnames.join(", "));
+    Ok(files)
+}
 
-    rustls_pemfile::certs(&mut reader).collect()
+fn dev_ca(cert_file: &Path, key_file: &Path) -> io::Result<Issuer<'static, KeyPair>> {
+    if cert_file.exists() && key_file.exists() {
+        let key = KeyPair::from_pem(&String::from_utf8_lossy(&read_file(key_file)?)).map_err(generate_error)?;
+        return Issuer::from_ca_cert_pem(&String::from_utf8_lossy(&read_file(cert_file)?), key).map_err(generate_error);
+    }
+    let mut params = CertificateParams::default();
+    params.distinguished_name.push(DnType::CommonName, "hyper-line development CA");
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    params.not_after = valid_for_days(DEV_CA_DAYS)?.to_datetime();
+    let key = KeyPair::generate().map_err(generate_error)?;
+    let cert = params.self_signed(&key).map_err(generate_error)?;
+    write_file(cert_file, cert.pem().as_bytes(), false)?;
+    write_file(key_file, key.serialize_pem().as_bytes(), true)?;
+    info!("Generated development CA {}, trust it to avoid certificate warnings", cert_file.display());
+    Ok(Issuer::new(params, key))
+}
+
+fn dev_certificate_usable(files: &DevCertificate, hostnames: &[String]) -> bool {
+    let Ok(key) = load_certified_key(&files.cert_file, &files.key_file) else { return false };
+    let Ok(cert) = leaf(&key) else { return false };
+    let covered = PeerIdentity::from_certificate(cert)
+        .map(|identity| hostnames.iter().all(|name| identity.sans.contains(&name.to_ascii_lowercase())))
+        .unwrap_or(false);
+    let fresh = not_after(cert)
+        .map(|expiry| expiry.timestamp() > unix_now() + 7 * 86400)
+        .unwrap_or(false);
+    covered && fresh
+}
+
+fn valid_for_days(days: i64) -> io::Result<ASN1Time> {
+    ASN1Time::from_timestamp(unix_now() + days * 86400).map_err(|e| error(e.to_string()))
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs() as i64).unwrap_or(0)
+}
+
+fn generate_error(err: rcgen::Error) -> io::Error {
+    error(format!("cannot generate certificate: {}", err))
+}
+
+/* private files are only ever readable by the owner, they are created with that mode and an
+   existing file, which may have a wider one, is replaced */
+fn write_file(path: &Path, content: &[u8], private: bool) -> io::Result<()> {
+    let failed = |e: io::Error| io::Error::new(e.kind(), format!("failed to write {}: {}", path.display(), e));
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(failed(e)),
+            _ => {}
+        }
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    let mut file = options.open(path).map_err(failed)?;
+    file.write_all(content).map_err(failed)
+}
+
+/// Certificate revocation lists of a PEM file, or the single CRL of a DER file.
+pub fn load_crls(path: &Path) -> io::Result<Vec<CertificateRevocationListDer<'static>>> {
+    let content = read_file(path)?;
+    let crls = if is_pem(&content) {
+        rustls_pemfile::crls(&mut content.as_slice())
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(|e| invalid(format!("invalid PEM in {}: {}", path.display(), e)))?
+    } else {
+        vec![CertificateRevocationListDer::from(content)]
+    };
+    if crls.is_empty() {
+        return Err(invalid(format!("no certificate revocation list found in {}", path.display())));
+    }
+    Ok(crls)
+}
+
+pub fn load_certs(filename: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    read_certs(Path::new(filename))
 }
 
 pub fn load_private_key(filename: &str) -> io::Result<PrivateKeyDer<'static>> {
-    let private_key = fs::File::open(filename).map_err(|e| {
-        error(format!("failed to open {}: {}", filename, e))
-    })?;
-    let mut reader = io::BufReader::new(private_key);
-    rustls_pemfile::private_key(&mut reader)
-        .map(|key| match key {
-            Some(x) => x,
-            None => panic!("Could not load private key: '{}'", filename),
-    })
-}
\ No newline at end of file
+    read_private_key(Path::new(filename))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_file(name: &str, content: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("hyper-line-{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_pem_and_der_formats() {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = temp_file("cert.der", generated.cert.der());
+        let key_der = temp_file("key.der", &generated.signing_key.serialize_der());
+        let bundle = temp_file(
+            "bundle.pem",
+            format!("{}{}", generated.cert.pem(), generated.signing_key.serialize_pem()).as_bytes(),
+        );
+
+        assert!(load_certified_key(&cert_der, &key_der).is_ok());
+        assert!(matches!(read_private_key(&key_der).unwrap(), PrivateKeyDer::Pkcs8(_)));
+        let mut manager = KeyManager::default();
+        manager.add_bundle(bundle.clone());
+        manager.add_cert("examples/tls/server.pem".into());
+        manager.add_key("examples/tls/server.rsa".into());
+        assert_eq!(manager.load().unwrap().len(), 2);
+        assert!(matches!(manager.load_keys().unwrap(), PrivateKeyDer::Pkcs8(_)));
+
+        for path in [cert_der, key_der, bundle] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    fn certified(names: &[&str]) -> Arc<CertifiedKey> {
+        let generated = rcgen::generate_simple_self_signed(names.iter().map(|n| n.to_string()).collect::<Vec<_>>()).unwrap();
+        let key = PrivateKeyDer::try_from(generated.signing_key.serialize_der()).unwrap();
+        let provider = rustls::crypto::aws_lc_rs::default_provider();
+        Arc::new(CertifiedKey::from_der(vec![generated.cert.der().clone()], key, &provider).unwrap())
+    }
+
+    #[test]
+    fn test_sni_resolution() {
+        let default = certified(&["default.test"]);
+        let exact = certified(&["api.example.com"]);
+        let wildcard = certified(&["*.example.com", "example.com"]);
+        let mut resolver = SniResolver::default();
+        resolver.add(default.clone()).unwrap();
+        resolver.add(wildcard.clone()).unwrap();
+        resolver.add(exact.clone()).unwrap();
+
+        assert!(Arc::ptr_eq(&resolver.lookup(Some("API.example.com")).unwrap(), &exact));
+        assert!(Arc::ptr_eq(&resolver.lookup(Some("www.example.com")).unwrap(), &wildcard));
+        assert!(Arc::ptr_eq(&resolver.lookup(Some("example.com")).unwrap(), &wildcard));
+        assert!(Arc::ptr_eq(&resolver.lookup(Some("a.b.example.com")).unwrap(), &default));
+        assert!(Arc::ptr_eq(&resolver.lookup(None).unwrap(), &default));
+    }
+
+    #[test]
+    fn test_resolver_reports_unknown_sni() {
+        let server_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(SniResolver::default()));
+        let client_config = rustls::ClientConfig::builder()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let mut client = rustls::ClientConnection::new(Arc::new(client_config), "unknown.test".try_into().unwrap()).unwrap();
+        let mut server = rustls::ServerConnection::new(Arc::new(server_config)).unwrap();
+        let mut hello = Vec::new();
+        client.write_tls(&mut hello).unwrap();
+        server.read_tls(&mut hello.as_slice()).unwrap();
+
+        let unknown = crate::server::UNKNOWN_SNI.sync_scope(std::cell::Cell::new(false), || {
+            assert!(server.process_new_packets().is_err());
+            crate::server::UNKNOWN_SNI.with(std::cell::Cell::get)
+        });
+        assert!(unknown, "Should report a handshake without a certificate to serve.");
+    }
+
+    #[test]
+    fn test_reload_swaps_valid_certificates_only() {
+        let write = |names: &[&str]| {
+            let generated = rcgen::generate_simple_self_signed(names.iter().map(|n| n.to_string()).collect::<Vec<_>>()).unwrap();
+            (generated.cert.pem(), generated.signing_key.serialize_pem())
+        };
+        let (cert, key) = write(&["old.test"]);
+        let cert_file = temp_file("reload.pem", cert.as_bytes());
+        let key_file = temp_file("reload.key", key.as_bytes());
+        let mut manager = KeyManager::default();
+        manager.add_cert(cert_file.clone());
+        manager.add_key(key_file.clone());
+        let reloader = CertReloader::new(manager).unwrap();
+        let old = reloader.current().lookup(Some("old.test")).unwrap();
+
+        let (cert, key) = write(&["new.test"]);
+        fs::write(&cert_file, cert).unwrap();
+        assert!(reloader.reload().is_err());
+        assert!(Arc::ptr_eq(&reloader.current().lookup(Some("new.test")).unwrap(), &old));
+
+        fs::write(&key_file, key).unwrap();
+        reloader.reload().unwrap();
+        let new = reloader.current().lookup(Some("old.test")).unwrap();
+        assert!(!Arc::ptr_eq(&new, &old));
+        assert_eq!(certificate_names(leaf(&new).unwrap()).unwrap(), ["new.test"]);
+        fs::remove_file(cert_file).unwrap();
+        fs::remove_file(key_file).unwrap();
+    }
+
+    #[test]
+    fn test_peer_identity() {
+        let mut params = rcgen::CertificateParams::new(vec!["client.example.com".to_string()]).unwrap();
+        params.distinguished_name.push(rcgen::DnType::CommonName, "billing");
+        params.subject_alt_names.push(rcgen::SanType::IpAddress("10.0.0.7".parse().unwrap()));
+        let cert = params.self_signed(&rcgen::KeyPair::generate().unwrap()).unwrap();
+
+        let identity = PeerIdentity::from_certificate(cert.der()).unwrap();
+        assert_eq!(identity.subject, "CN=billing");
+        assert_eq!(identity.sans, ["client.example.com", "10.0.0.7"]);
+        assert_eq!(identity.fingerprint.len(), 64);
+    }
+
+    #[test]
+    fn test_dev_certificate_with_ca_is_reused() {
+        let dir = std::env::temp_dir().join(format!("hyper-line-{}-dev-certs", std::process::id()));
+        let hostnames = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+        let files = dev_certificate(&dir, &hostnames, true).unwrap();
+        let first = fs::read(&files.cert_file).unwrap();
+        assert_eq!(read_certs(&files.cert_file).unwrap().len(), 2);
+
+        let ca = load_certs(&files.ca_file.as_ref().unwrap().to_string_lossy()).unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca[0].clone()).unwrap();
+        assert!(rustls::client::WebPkiServerVerifier::builder(Arc::new(roots)).build().is_ok());
+
+        dev_certificate(&dir, &hostnames, true).unwrap();
+        assert_eq!(fs::read(&files.cert_file).unwrap(), first);
+        dev_certificate(&dir, &["example.test".to_string()], true).unwrap();
+        assert_ne!(fs::read(&files.cert_file).unwrap(), first);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_expiry_of_served_certificates() {
+        let mut params = CertificateParams::new(vec!["soon.test".to_string()]).unwrap();
+        params.not_after = valid_for_days(3).unwrap().to_datetime();
+        let key = KeyPair::generate().unwrap();
+        let cert_file = temp_file("expiry.pem", params.self_signed(&key).unwrap().pem().as_bytes());
+        let key_file = temp_file("expiry.key", key.serialize_pem().as_bytes());
+        let mut manager = KeyManager::default();
+        manager.add_cert(cert_file.clone());
+        manager.add_key(key_file.clone());
+
+        let reloader = CertReloader::new(manager).unwrap();
+        let expiry = reloader.check_expiry(Duration::from_secs(30 * 86400));
+        assert_eq!(expiry.len(), 1);
+        assert_eq!(expiry[0].names, ["soon.test"]);
+        assert!((2..=3).contains(&expiry[0].days_until_expiry));
+        assert!(!expiry[0].expired());
+        let gauge = format!("tls_certificate_days_until_expiry{{cert_file=\"{}\",names=\"soon.test\"}} {}\n", cert_file.display(), expiry[0].days_until_expiry);
+        assert!(reloader.expiry_metrics().ends_with(&gauge), "{}", reloader.expiry_metrics());
+        fs::remove_file(cert_file).unwrap();
+        fs::remove_file(key_file).unwrap();
+    }
+
+    #[test]
+    fn test_ocsp_response_is_stapled_until_expired() {
+        let generated = rcgen::generate_simple_self_signed(vec!["ocsp.test".to_string()]).unwrap();
+        let cert_file = temp_file("stapled.pem", generated.cert.pem().as_bytes());
+        let key_file = temp_file("stapled.key", generated.signing_key.serialize_pem().as_bytes());
+        let ocsp_file = ocsp::response_file(&cert_file);
+        fs::write(&ocsp_file, ocsp::test::response(generated.cert.der(), Some(unix_now() + 86400))).unwrap();
+
+        let mut manager = KeyManager::default();
+        manager.add_cert(cert_file.clone());
+        manager.add_key(key_file.clone());
+        let reloader = CertReloader::new(manager).unwrap();
+        assert!(reloader.current().lookup(None).unwrap().ocsp.is_some());
+
+        fs::write(&ocsp_file, ocsp::test::response(generated.cert.der(), Some(unix_now() - 60))).unwrap();
+        reloader.reload().unwrap();
+        assert!(reloader.current().lookup(None).unwrap().ocsp.is_none());
+        for path in [cert_file, key_file, ocsp_file] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_errors_are_descriptive() {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let other_key = temp_file("other.pem", generated.signing_key.serialize_pem().as_bytes());
+        let mismatch = load_certified_key(Path::new("examples/tls/server.pem"), &other_key).err().unwrap();
+        assert!(mismatch.to_string().contains("does not fit certificate"), "{}", mismatch);
+
+        let no_key = load_private_key("examples/tls/server.pem").err().unwrap();
+        assert!(no_key.to_string().contains("no PKCS#1, PKCS#8 or SEC1 private key"), "{}", no_key);
+        assert_eq!(load_certs("missing.pem").err().unwrap().kind(), io::ErrorKind::NotFound);
+        fs::remove_file(other_key).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_private_files_are_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let key_file = temp_file("private.pem", b"old");
+        fs::set_permissions(&key_file, fs::Permissions::from_mode(0o644)).unwrap();
+        write_file(&key_file, b"key", true).unwrap();
+        assert_eq!(fs::metadata(&key_file).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read(&key_file).unwrap(), b"key");
+        fs::remove_file(key_file).unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+use std::path::{Path, PathBuf};
+use aws_lc_rs::digest;
+use log::{info, warn};
+use rustls::pki_types::CertificateDer;
+use rustls::sign::CertifiedKey;
+use x509_parser::prelude::FromDer;
+use x509_parser::time::ASN1Time;
+
+/* id-pkix-ocsp-basic, 1.3.6.1.5.5.7.48.1.1 */
+const OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+
+const SEQUENCE: u8 = 0x30;
+const GENERALIZED_TIME: u8 = 0x18;
+const CERT_STATUS_GOOD: u8 = 0x80;
+const CERT_STATUS_REVOKED: u8 = 0xa1;
+
+/* responses without a nextUpdate are read again after this long, in case a newer one arrived */
+const RECHECK_SECS: i64 = 3600;
+
+/// OCSP response stapled for `cert_file`: the DER file `<cert_file>.ocsp`, e.g. `server.pem.ocsp`.
+pub fn response_file(cert_file: &Path) -> PathBuf {
+    let mut path = cert_file.as_os_str().to_owned();
+    path.push(".ocsp");
+    PathBuf::from(path)
+}
+
+/// Staples the OCSP response found next to `cert_file` if it is good for the certificate and
+/// not expired, returning when the response has to be refreshed by.
+pub fn staple(key: &mut CertifiedKey, cert_file: &Path, now: i64) -> Option<i64> {
+    let file = response_file(cert_file);
+    let response = std::fs::read(&file).ok()?;
+    match check_response(&response, &key.cert, now) {
+        Ok(next_update) => {
+            info!("Stapling OCSP response {}", file.display());
+            key.ocsp = Some(response);
+            Some(next_update)
+        }
+        Err(e) => {
+            warn!("Not stapling OCSP response {}: {}", file.display(), e);
+            None
+        }
+    }
+}
+
+/// Checks that a DER `OCSPResponse` is successful, reports the first certificate of `chain` as
+/// good and is still current, returning its `nextUpdate`, or a time an hour from `now` when it
+/// has none. The certificate is identified by its serial and issuer name, and by the issuer key
+/// when the issuer is part of the chain or the certificate is self-issued. Signatures are left
+/// to the clients.
+pub fn check_response(response: &[u8], chain: &[CertificateDer<'_>], now: i64) -> Result<i64, String> {
+    let cert = chain.first().ok_or_else(|| "no certificate".to_string())?;
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert).map_err(|e| format!("cannot parse certificate: {}", e))?;
+    let serial = parsed.raw_serial();
+    let issuer_name = parsed.issuer().as_raw();
+    let issuer_key = match chain.get(1) {
+        Some(issuer) => {
+            let (_, issuer) = x509_parser::parse_x509_certificate(issuer)
+                .map_err(|e| format!("cannot parse issuer certificate: {}", e))?;
+            Some(issuer.public_key().subject_public_key.data.to_vec())
+        }
+        None if issuer_name == parsed.subject().as_raw() => Some(parsed.public_key().subject_public_key.data.to_vec()),
+        None => None,
+    };
+
+    let (ocsp_response, _) = expect(response, SEQUENCE)?;
+    let (status, rest) = expect(ocsp_response, 0x0a)?;
+    if status != [0] {
+        return Err(format!("responder returned status {:?}", status));
+    }
+    let (response_bytes, _) = expect(rest, 0xa0)?;
+    let (response_bytes, _) = expect(response_bytes, SEQUENCE)?;
+    let (response_type, rest) = expect(response_bytes, 0x06)?;
+    if response_type != OCSP_BASIC {
+        return Err("not a basic OCSP response".to_string());
+    }
+    let (basic, _) = expect(rest, 0x04)?;
+    let (basic, _) = expect(basic, SEQUENCE)?;
+    let (response_data, _) = expect(basic, SEQUENCE)?;
+
+    /* version, responderID and producedAt come before the responses */
+    let mut rest = response_data;
+    let responses = loop {
+        let (tag, content, next) = tlv(rest)?;
+        if tag == SEQUENCE {
+            break content;
+        }
+        rest = next;
+    };
+
+    let mut rest = responses;
+    while !rest.is_empty() {
+        let (single, next) = expect(rest, SEQUENCE)?;
+        rest = next;
+        let (cert_id, fields) = expect(single, SEQUENCE)?;
+        let (hash_algorithm, cert_id) = expect(cert_id, SEQUENCE)?;
+        let (name_hash, cert_id) = expect(cert_id, 0x04)?;
+        let (key_hash, cert_id) = expect(cert_id, 0x04)?;
+        let (serial_number, _) = expect(cert_id, 0x02)?;
+        if serial_number != serial {
+            continue;
+        }
+        let (algorithm, _) = expect(hash_algorithm, 0x06)?;
+        let algorithm = hash_algorithm_of(algorithm)?;
+        if digest::digest(algorithm, issuer_name).as_ref() != name_hash {
+            continue;
+        }
+        if issuer_key.as_ref().is_some_and(|key| digest::digest(algorithm, key).as_ref() != key_hash) {
+            continue;
+        }
+
+        let (cert_status, _, fields) = tlv(fields)?;
+        match cert_status {
+            CERT_STATUS_GOOD => {}
+            CERT_STATUS_REVOKED => return Err("certificate is revoked".to_string()),
+            _ => return Err("certificate status is unknown to the responder".to_string()),
+        }
+        let (_, fields) = expect(fields, GENERALIZED_TIME)?; /* thisUpdate */
+        let Ok((next_update, _)) = expect(fields, 0xa0) else {
+            return Ok(now + RECHECK_SECS);
+        };
+        let next_update = ASN1Time::from_der(next_update)
+            .map_err(|e| format!("invalid nextUpdate: {}", e))?
+            .1
+            .timestamp();
+        if next_update <= now {
+            return Err("response has expired".to_string());
+        }
+        return Ok(next_update);
+    }
+    Err("response is for a different certificate".to_string())
+}
+
+/* the digest named by the hashAlgorithm of a CertID */
+fn hash_algorithm_of(oid: &[u8]) -> Result<&'static digest::Algorithm, String> {
+    match oid {
+        [0x2b, 0x0e, 0x03, 0x02, 0x1a] => Ok(&digest::SHA1_FOR_LEGACY_USE_ONLY),
+        [0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01] => Ok(&digest::SHA256),
+        [0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02] => Ok(&digest::SHA384),
+        [0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03] => Ok(&digest::SHA512),
+        _ => Err("unsupported CertID hash algorithm".to_string()),
+    }
+}
+
+fn expect(input: &[u8], expected: u8) -> Result<(&[u8], &[u8]), String> {
+    let (tag, content, rest) = tlv(input)?;
+    if tag != expected {
+        return Err(format!("malformed response, expected tag {:#04x} but found {:#04x}", expected, tag));
+    }
+    Ok((content, rest))
+}
+
+/* splits the first DER element into its tag, content and what follows it */
+fn tlv(input: &[u8]) -> Result<(u8, &[u8], &[u8]), String> {
+    let truncated = || "malformed response, truncated".to_string();
+    let (&tag, rest) = input.split_first().ok_or_else(truncated)?;
+    let (&first, rest) = rest.split_first().ok_or_else(truncated)?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return Err(truncated());
+        }
+        let len = rest[..count].iter().fold(0usize, |len, byte| (len << 8) | *byte as usize);
+        (len, &rest[count..])
+    };
+    if rest.len() < len {
+        return Err(truncated());
+    }
+    Ok((tag, &rest[..len], &rest[len..]))
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        match content.len() {
+            len if len < 0x80 => out.push(len as u8),
+            len if len < 0x100 => out.extend([0x81, len as u8]),
+            len => out.extend([0x82, (len >> 8) as u8, len as u8]),
+        }
+        out.extend_from_slice(content);
+        out
+    }
+
+    fn time(unix: i64) -> Vec<u8> {
+        let time = ASN1Time::from_timestamp(unix).unwrap().to_datetime();
+        let text = format!(
+            "{:04}{:02}{:02}{:02}{:02}{:02}Z",
+            time.year(),
+            time.month() as u8,
+            time.day(),
+            time.hour(),
+            time.minute(),
+            time.second()
+        );
+        der(GENERALIZED_TIME, text.as_bytes())
+    }
+
+    /* CertID of a self-signed certificate, with its serial replaced when `serial` is set */
+    fn cert_id(cert: &[u8], serial: Option<&[u8]>) -> Vec<u8> {
+        let (_, parsed) = x509_parser::parse_x509_certificate(cert).unwrap();
+        let sha1 = |data: &[u8]| digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, data).as_ref().to_vec();
+        [
+            der(SEQUENCE, &der(0x06, &[0x2b, 0x0e, 0x03, 0x02, 0x1a])),
+            der(0x04, &sha1(parsed.issuer().as_raw())),
+            der(0x04, &sha1(&parsed.public_key().subject_public_key.data)),
+            der(0x02, serial.unwrap_or(parsed.raw_serial())),
+        ]
+        .concat()
+    }
+
+    /// A good, unsigned OCSP response for the self-signed certificate `cert`.
+    pub(crate) fn response(cert: &[u8], next_update: Option<i64>) -> Vec<u8> {
+        build_response(&cert_id(cert, None), 1_800_000_000, next_update)
+    }
+
+    fn build_response(cert_id: &[u8], this_update: i64, next_update: Option<i64>) -> Vec<u8> {
+        let single = [
+            der(SEQUENCE, cert_id),
+            der(CERT_STATUS_GOOD, &[]),
+            time(this_update),
+            next_update.map(|next| der(0xa0, &time(next))).unwrap_or_default(),
+        ]
+        .concat();
+        let response_data = [
+            der(0xa2, &der(0x04, &[0; 20])),
+            time(this_update),
+            der(SEQUENCE, &der(SEQUENCE, &single)),
+        ]
+        .concat();
+        let basic = der(SEQUENCE, &[
+            der(SEQUENCE, &response_data),
+            der(SEQUENCE, &der(0x06, &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02])),
+            der(0x03, &[0, 0]),
+        ]
+        .concat());
+        let response_bytes = der(SEQUENCE, &[der(0x06, OCSP_BASIC), der(0x04, &basic)].concat());
+        der(SEQUENCE, &[der(0x0a, &[0]), der(0xa0, &response_bytes)].concat())
+    }
+
+    #[test]
+    fn test_response_checks() {
+        let generated = rcgen::generate_simple_self_signed(vec!["ocsp.test".to_string()]).unwrap();
+        let cert = generated.cert.der();
+        let chain = [cert.clone()];
+        let now = 1_800_000_000;
+
+        assert_eq!(check_response(&response(cert, Some(now + 86400)), &chain, now), Ok(now + 86400));
+        assert_eq!(check_response(&response(cert, Some(now - 60)), &chain, now), Err("response has expired".to_string()));
+        assert_eq!(check_response(&response(cert, None), &chain, now), Ok(now + RECHECK_SECS));
+        assert!(check_response(&build_response(&cert_id(cert, Some(&[1, 2, 3])), now, None), &chain, now).is_err());
+        assert!(check_response(&response(cert, Some(now + 86400))[..40], &chain, now).is_err());
+
+        /* same serial, but issued by another CA */
+        let other = rcgen::generate_simple_self_signed(vec!["other.test".to_string()]).unwrap();
+        let (_, parsed) = x509_parser::parse_x509_certificate(cert).unwrap();
+        let foreign = cert_id(other.cert.der(), Some(parsed.raw_serial()));
+        assert!(check_response(&build_response(&foreign, now, None), &chain, now).is_err());
+    }
+}
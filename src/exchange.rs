@@ -1,5 +1,6 @@
 use std::any::{Any, TypeId};
 use std::collections::HashMap;
+use std::fmt;
 use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, Not};
 
 pub struct Exchange<I, O>
@@ -143,7 +144,7 @@ where
 
     fn execute_callbacks(
         &self,
-        callbacks: &Vec<Callback<Self>>
+        callbacks: &[Callback<Self>]
     ) -> Result<(), ()>
     where
         Self: Send
@@ -170,7 +171,7 @@ where
 
     pub fn input(
         &self
-    ) -> Result<&I, ()>
+    ) -> Result<&I, ExchangeError>
     {
         if self.status.all_flags_clear(Status::INPUT_CONSUMED) {
             return Ok(&self.input);
@@ -178,12 +179,12 @@ where
 
         log::error!("A request has already been saved for this exchange.");
 
-        Err(())
+        Err(ExchangeError::InputConsumed)
     }
 
     pub fn consume_request(
         &mut self
-    ) -> Result<I, ()>
+    ) -> Result<I, ExchangeError>
     {
         if self.status.all_flags_clear(Status::INPUT_CONSUMED) {
             self.status |= Status::INPUT_CONSUMED;
@@ -199,7 +200,7 @@ where
             }
 
         }
-        Err(())
+        Err(ExchangeError::InputConsumed)
     }
 
     pub fn save_output(
@@ -212,7 +213,7 @@ where
 
     pub fn consume_output(
         &mut self
-    ) -> Result<O, ()>
+    ) -> Result<O, ExchangeError>
     {
         if self.status.all_flags_clear(Status::OUTPUT_CONSUMED) {
             self.status |= Status::OUTPUT_CONSUMED;
@@ -233,7 +234,7 @@ where
             }
 
         }
-        Err(())
+        Err(ExchangeError::OutputConsumed)
     }
 
     pub fn status(&self) -> &Status {
@@ -241,6 +242,36 @@ where
     }
 }
 
+impl<I, O> Default for Exchange<I, O>
+where
+    I: Default + Send + 'static,
+    O: Default + Send + 'static
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Why the input or output of an exchange is not available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExchangeError {
+    /// The request has already been consumed by a handler.
+    InputConsumed,
+    /// The response has already been consumed.
+    OutputConsumed,
+}
+
+impl fmt::Display for ExchangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExchangeError::InputConsumed => f.write_str("exchange input has already been consumed"),
+            ExchangeError::OutputConsumed => f.write_str("exchange output has already been consumed"),
+        }
+    }
+}
+
+impl std::error::Error for ExchangeError {}
+
 /* I wanted to make this struct use TypeId::of::<>() but it's not stable. */
 #[derive(PartialOrd, PartialEq, Hash, Eq)]
 pub struct AttachmentKey(pub u32);
@@ -250,10 +281,16 @@ impl AttachmentKey {
     pub const APP_CONTEXT: AttachmentKey = AttachmentKey(1);
     pub const CLIENT_SRC: AttachmentKey = AttachmentKey(2);
     pub const CACHED_BODY: AttachmentKey = AttachmentKey(3);
+    pub const REQUEST_ID: AttachmentKey = AttachmentKey(4);
+    pub const ROUTE_PARAMS: AttachmentKey = AttachmentKey(5);
+    pub const PROXY_ERROR: AttachmentKey = AttachmentKey(6);
+    pub const PEER_IDENTITY: AttachmentKey = AttachmentKey(7);
 }
 
+type CallbackFn<T> = Box<dyn Fn(Box<&T>) + Send + 'static>;
+
 pub struct Callback<T: Send + ?Sized> {
-    callback: Box<dyn Fn(Box<&T>) + Send + 'static>
+    callback: CallbackFn<T>
 }
 impl<T: Send + ?Sized> Callback<T> {
     pub fn new(
@@ -283,7 +320,7 @@ pub const fn i32_bit_mask(low: i32, high: i32) -> i32 {
     if high == 31 {
         0
     } else {
-        (1 << high + 1) - (1 << low)
+        (1 << (high + 1)) - (1 << low)
     }
 }
 
@@ -361,6 +398,7 @@ impl BitOr for Status {
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod test {
     use log::info;
     use super::*;
@@ -384,7 +422,7 @@ mod test {
     #[test]
     fn test_custom_listener() {
         let mut ex: Exchange<usize, usize> = Exchange::new();
-        ex.add_custom_listener(|ex| {
+        ex.add_custom_listener(|_ex| {
             info!("This is a custom listener executing...");
         });
 
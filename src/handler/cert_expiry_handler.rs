@@ -0,0 +1,85 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use crate::cert_manager::CertReloader;
+use hyper::{Response, StatusCode};
+use crate::body::{empty, full};
+use crate::exchange::{AttachmentKey, Exchange};
+use crate::handler::Handler;
+use crate::server::ServerConfig;
+use crate::{HttpRequest, HttpResponse};
+
+/// Admin endpoint listing the served certificates with their days until expiry as JSON.
+/// Responds 404 when the server was not set up from a `TlsConfig`.
+#[derive(Debug, Clone, Default)]
+pub struct CertExpiryHandler;
+
+impl Handler<HttpRequest, HttpResponse> for CertExpiryHandler {
+    fn process<'i1, 'i2, 'o>(
+        &'i1 self,
+        context: &'i2 mut Exchange<HttpRequest, HttpResponse>
+    ) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send + 'o>>
+    where
+        'i1: 'o,
+        'i2: 'o,
+        Self: 'o
+    {
+        Box::pin(async move {
+            let expiry = certificates(context).map(|certificates| certificates.expiry());
+            let response = match expiry {
+                Some(expiry) => {
+                    let body = serde_json::to_vec(&expiry).map_err(|_| ())?;
+                    let mut response = Response::new(full(body));
+                    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                    response
+                }
+                None => not_found(),
+            };
+            context.save_output(response);
+            Ok(())
+        })
+    }
+}
+
+/// Metrics endpoint serving the days until expiry of each served certificate as a gauge in the
+/// Prometheus text format. Responds 404 when the server was not set up from a `TlsConfig`.
+#[derive(Debug, Clone, Default)]
+pub struct CertExpiryMetricsHandler;
+
+impl Handler<HttpRequest, HttpResponse> for CertExpiryMetricsHandler {
+    fn process<'i1, 'i2, 'o>(
+        &'i1 self,
+        context: &'i2 mut Exchange<HttpRequest, HttpResponse>
+    ) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send + 'o>>
+    where
+        'i1: 'o,
+        'i2: 'o,
+        Self: 'o
+    {
+        Box::pin(async move {
+            let response = match certificates(context) {
+                Some(certificates) => {
+                    let mut response = Response::new(full(certificates.expiry_metrics()));
+                    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; version=0.0.4"));
+                    response
+                }
+                None => not_found(),
+            };
+            context.save_output(response);
+            Ok(())
+        })
+    }
+}
+
+fn certificates(context: &Exchange<HttpRequest, HttpResponse>) -> Option<Arc<CertReloader>> {
+    context
+        .attachment::<Arc<ServerConfig>>(AttachmentKey::APP_CONTEXT)
+        .and_then(|config| config.certificates.clone())
+}
+
+fn not_found() -> HttpResponse {
+    let mut response = Response::new(empty());
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    response
+}
@@ -1,10 +1,10 @@
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::Arc;
 use std::time::{SystemTime};
-use log::{debug, error, info};
+use log::{error, info};
 use crate::exchange::{Exchange, AttachmentKey};
 use crate::handler::{Handler};
+use crate::handler::reverse_proxy_handler::ProxyError;
 
 
 #[derive(Debug, Clone, Default)]
@@ -57,7 +57,7 @@ where
             context.add_output_listener(move |exchange| {
                 let trace = match exchange.attachment::<SystemTime>(TRACE_TIME) {
                     None => return,
-                    Some(trace) => trace.clone()
+                    Some(trace) => *trace
                 };
                 let elapsed = match SystemTime::now().duration_since(trace) {
                     Ok(elapsed) => elapsed,
@@ -66,7 +66,10 @@ where
                         return;
                     }
                 };
-                info!("Exchange process duration: {}ms", elapsed.as_millis());
+                match exchange.attachment::<ProxyError>(AttachmentKey::PROXY_ERROR) {
+                    Some(err) => info!("Exchange process duration: {}ms, proxy error: {}", elapsed.as_millis(), err),
+                    None => info!("Exchange process duration: {}ms", elapsed.as_millis()),
+                }
             });
             Ok(())
         })
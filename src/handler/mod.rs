@@ -1,5 +1,6 @@
 pub mod reverse_proxy_handler;
 pub mod exchange_trace_handler;
+pub mod cert_expiry_handler;
 
 use std::collections::HashMap;
 use std::future::Future;
@@ -10,7 +11,7 @@ use crate::{HttpBody, HttpRequest, HttpResponse};
 
 pub type HttpHandler = Box<dyn Handler<HttpBody, HttpBody> + Send + Sync + 'static>;
 
-static REGISTERED_HANDLERS: LazyLock<RwLock<HashMap<HandlerId, Arc<dyn Handler<HttpRequest, HttpResponse> + Sync + Send + 'static>>>> = LazyLock::new(|| RwLock::new(HashMap::new()));
+static REGISTERED_HANDLERS: LazyLock<RwLock<HashMap<HandlerId, crate::HttpHandler>>> = LazyLock::new(|| RwLock::new(HashMap::new()));
 pub fn register(id: &str, handler: Arc<dyn Handler<HttpRequest, HttpResponse> + Sync + Send + 'static>) {
     REGISTERED_HANDLERS.write().unwrap().insert(HandlerId(id.to_string()), handler);
 }
@@ -1,74 +1,158 @@
 use crate::server::ServerConfig;
 use crate::exchange::{Exchange, AttachmentKey};
 use crate::handler::Handler;
-use crate::{HttpBody, HttpRequest, HttpResponse};
+use crate::{BoxError, HttpBody, HttpRequest, HttpResponse};
+use crate::body::{buffer_limited, empty, full, Buffered, ErrorTrailersBody};
 use http_body_util::BodyExt;
 use http_body_util::Empty;
 use hyper::body::Bytes;
 use hyper::client::conn;
-use hyper::header::{HeaderName, HeaderValue, InvalidHeaderValue, ToStrError};
+use hyper::header::{HeaderName, HeaderValue, InvalidHeaderValue, ToStrError, SET_COOKIE};
+use hyper::http::request;
 use hyper::http::uri::InvalidUri;
 use hyper::{Error, HeaderMap, Request, Response, StatusCode, Uri};
-use hyper_rustls::{ConfigBuilderExt, HttpsConnector};
-use hyper_util::client::legacy::connect::HttpConnector;
-use hyper_util::client::legacy::{connect::Connect, Client, Error as LegacyError};
-use hyper_util::rt::{TokioIo, TokioTimer};
+use hyper_util::client::legacy::{Client, Error as LegacyError};
+use hyper_util::rt::TokioIo;
 use log::{debug, warn};
+use rand::Rng;
 use rustls::ClientConfig as TlsClientConfig;
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error as _;
+use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::future::Future;
-use std::io::Read;
-use std::net::{IpAddr, SocketAddr};
+use std::io::{self, Read};
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::{Arc, OnceLock};
 use std::time::Duration;
-use tokio::io::copy_bidirectional;
-use tokio::net::TcpStream;
-use crate::service::ServiceExecutor;
-
-fn proxy_client(config: &ServerConfig) -> &'static ReverseProxy<HttpsConnector<HttpConnector>> {
-    static PROXY_CLIENT: OnceLock<ReverseProxy<HttpsConnector<HttpConnector>>> = OnceLock::new();
-    PROXY_CLIENT.get_or_init(|| {
-        // TODO: Allow configuration to be separate from the server certs.
-        let connector = if config.tls_enabled {
-            match &config.tls_client_config {
-                None => panic!("TLS Enabled, but no client configuration is set!"),
-                Some(config) => HttpsConnector::<HttpConnector>::builder()
-                    .with_tls_config(config.clone())
-                    .https_or_http()
-                    .enable_http2()
-                    .build(),
-            }
-        } else {
-            HttpsConnector::<HttpConnector>::builder()
-                .with_tls_config(
-                    TlsClientConfig::builder()
-                        .with_webpki_roots()
-                        .with_no_client_auth(),
-                )
-                .https_or_http()
-                .enable_http2()
-                .build()
-        };
-
-        return ReverseProxy::new(
-            hyper_util::client::legacy::Builder::new(ServiceExecutor)
-                .pool_idle_timeout(Duration::from_secs(3))
-                .pool_timer(TokioTimer::new())
-                .build::<_, HttpBody>(connector),
-        );
-    })
+use crate::proxy::discovery::{DiscoveryConfig, ServiceDiscovery};
+use crate::proxy::endpoint::Endpoint;
+use crate::proxy::client::{build_client, default_tls_client_config, ClientSettings, ProxyConnector, UpstreamConnect};
+use crate::proxy::circuit_breaker::{CircuitBreakerConfig, CircuitBreakers, CircuitState};
+use crate::proxy::forwarding::{ForwardedInfo, ForwardingPolicy};
+use crate::proxy::headers::{HeaderRules, HeaderVars};
+use crate::proxy::grpc::{self, is_grpc, status_trailers, GrpcStatus};
+use crate::proxy::mirror::{MirrorConfig, MirrorMetrics, MirrorStats};
+use crate::proxy::retry::{RetryBudget, RetryPolicy};
+use crate::proxy::rewrite::RewriteConfig;
+use crate::proxy::split::TrafficSplit;
+use crate::proxy::timeout::{is_connect_timeout, ProxyTimeouts, TimeoutBody};
+use crate::proxy::tls::UpstreamTlsConfig;
+use crate::proxy::tunnel::{run_tunnel, TunnelMetrics, TunnelStats};
+
+/// Fails with a `ProxyError::Timeout` when `fut` does not complete within `limit`.
+async fn with_timeout<T, F>(limit: Option<Duration>, fut: F, describe: impl FnOnce(Duration) -> String) -> Result<T, ProxyError>
+where
+    F: Future<Output = Result<T, ProxyError>>,
+{
+    match limit {
+        None => fut.await,
+        Some(limit) => match tokio::time::timeout(limit, fut).await {
+            Ok(result) => result,
+            Err(_) => Err(ProxyError::Timeout(describe(limit))),
+        },
+    }
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct ReverseProxyHandler {
     proxy_config: ProxyConfig,
+    /* built up front from the handler's own TLS settings, otherwise on the first request from
+       the server's `tls_client_config` */
+    client: OnceLock<ReverseProxy<ProxyConnector>>,
+    retry_budget: Arc<RetryBudget>,
+    circuit_breakers: Arc<CircuitBreakers>,
+    mirror_metrics: Arc<MirrorMetrics>,
+    discovery: Option<Arc<ServiceDiscovery>>,
+}
+
+impl Default for ReverseProxyHandler {
+    fn default() -> Self {
+        /* the default config has no TLS files to load */
+        Self::unconnected(ProxyConfig::default())
+    }
 }
 
 impl ReverseProxyHandler {
-    pub const fn new(proxy_config: ProxyConfig) -> Self {
-        Self { proxy_config }
+    /// Creates the handler with its own client. With a `tls` section in `proxy_config` the client
+    /// is set up from it right away, failing when its files cannot be loaded or its `server_name`
+    /// is invalid. Otherwise the client uses the server's `tls_client_config`, set up on the first
+    /// request, falling back to the webpki roots when the server has none.
+    pub fn try_new(proxy_config: ProxyConfig) -> io::Result<Self> {
+        let handler = Self::unconnected(proxy_config);
+        if let Some(tls) = &handler.proxy_config.tls {
+            handler.connect(tls.client_config()?)?;
+        }
+        Ok(handler)
+    }
+
+    /// Like `try_new`, but panics when the upstream TLS settings are invalid.
+    pub fn expect_new(proxy_config: ProxyConfig) -> Self {
+        Self::try_new(proxy_config).unwrap_or_else(|e| panic!("Invalid upstream TLS configuration: {}", e))
+    }
+
+    /// Creates the handler with its own client, using `tls_config` for https upstreams instead
+    /// of the one described by the `tls` section of `proxy_config` or the server's. Fails when
+    /// the `server_name` of that section is invalid.
+    pub fn with_tls_client_config(proxy_config: ProxyConfig, tls_config: TlsClientConfig) -> io::Result<Self> {
+        let handler = Self::unconnected(proxy_config);
+        handler.connect(tls_config)?;
+        Ok(handler)
+    }
+
+    fn unconnected(proxy_config: ProxyConfig) -> Self {
+        let retry_budget = Arc::new(RetryBudget::new(proxy_config.retry.budget.clone()));
+        let circuit_breakers = Arc::new(CircuitBreakers::new(proxy_config.circuit_breaker.clone()));
+        let discovery = proxy_config.discovery.clone().map(ServiceDiscovery::new);
+        Self {
+            proxy_config,
+            client: OnceLock::new(),
+            retry_budget,
+            circuit_breakers,
+            mirror_metrics: Arc::new(MirrorMetrics::default()),
+            discovery,
+        }
+    }
+
+    fn connect(&self, tls_config: TlsClientConfig) -> io::Result<()> {
+        let server_name = match &self.proxy_config.tls {
+            Some(tls) => tls.server_name()?,
+            None => None,
+        };
+        let config = &self.proxy_config;
+        self.client.get_or_init(|| build_client(&config.client, &config.timeouts, tls_config, server_name));
+        Ok(())
+    }
+
+    /* the client set up by the constructor, or one using the TLS client settings of `server`;
+       handlers with a `tls` section of their own are always set up by the constructor */
+    fn client(&self, server: &ServerConfig) -> &ReverseProxy<ProxyConnector> {
+        self.client.get_or_init(|| {
+            let tls_config = server.tls_client_config.clone().unwrap_or_else(default_tls_client_config);
+            build_client(&self.proxy_config.client, &self.proxy_config.timeouts, tls_config, None)
+        })
+    }
+
+    /// Connection and byte counts of the upgraded connections this handler has tunneled.
+    pub fn tunnel_stats(&self) -> TunnelStats {
+        self.client.get().map(ReverseProxy::tunnel_stats).unwrap_or_default()
+    }
+
+    /// Service discovery of this handler's cluster, when configured.
+    pub fn discovery(&self) -> Option<&Arc<ServiceDiscovery>> {
+        self.discovery.as_ref()
+    }
+
+    /// Counts of the requests this handler has mirrored and how they went.
+    pub fn mirror_stats(&self) -> MirrorStats {
+        self.mirror_metrics.snapshot()
+    }
+
+    /// Circuit breaker state of every upstream endpoint this handler has called.
+    pub fn circuit_states(&self) -> Vec<(Endpoint, CircuitState)> {
+        self.circuit_breakers.states()
     }
 
     fn destination_host(&self) -> &String {
@@ -78,6 +162,193 @@ impl ReverseProxyHandler {
     fn destination_port(&self) -> u16 {
         self.proxy_config.destination_port
     }
+
+    /// The endpoints of the group the traffic split picks, or the discovered members, or else the
+    /// destination followed by any additional endpoints, in the order attempts use them. Also
+    /// returns the affinity cookie to issue to the client, if any.
+    async fn endpoints(&self, headers: &HeaderMap, tls: bool) -> (Vec<Endpoint>, Option<HeaderValue>) {
+        if let Some(route) = self.proxy_config.split.route(headers) {
+            debug!("Routing request to upstream group {}", route.group);
+            return (route.endpoints, route.set_cookie);
+        }
+        if let Some(discovery) = &self.discovery {
+            let members = discovery.endpoints().await;
+            self.circuit_breakers.update_discovered(&members);
+            if !members.is_empty() {
+                /* start at a random member so load spreads over the cluster */
+                let start = rand::rng().random_range(0..members.len());
+                let endpoints = members[start..].iter().chain(&members[..start]).cloned().collect();
+                return (endpoints, None);
+            }
+            warn!("No discovered upstream endpoints, using the configured destination");
+        }
+        let destination = match self.destination_host().strip_prefix("unix:") {
+            Some(path) => Endpoint::unix(path),
+            None => Endpoint::new(self.destination_host(), self.destination_port(), tls),
+        };
+        let mut endpoints = vec![destination];
+        endpoints.extend(self.proxy_config.endpoints.iter().cloned());
+        (endpoints, None)
+    }
+
+    async fn forward<T: UpstreamConnect>(
+        &self,
+        proxy: &ReverseProxy<T>,
+        forwarded: &ForwardedInfo,
+        vars: &HeaderVars,
+        endpoints: &[Endpoint],
+        mut req: HttpRequest,
+    ) -> Result<HttpResponse, ProxyError> {
+        let policy = &self.proxy_config.retry;
+        self.retry_budget.record_request();
+        self.proxy_config.rewrite.rewrite_request_path(&mut req);
+
+        /* upgrades and gRPC streams cannot be buffered for a replay or a mirror */
+        let streaming = get_upgrade_type(req.headers()).is_some() || is_grpc(req.headers());
+        let retryable = policy.enabled() && policy.allows_method(req.method());
+        let mirror = self.proxy_config.mirror.sample().filter(|_| !streaming);
+        if streaming || (!retryable && mirror.is_none()) {
+            return self.attempt(proxy, forwarded, vars, &endpoints[0], req).await;
+        }
+
+        let (parts, body) = req.into_parts();
+        if let (false, Some(mirror)) = (retryable, mirror) {
+            /* only the mirror needs the body in memory, bodies above its limit are streamed */
+            let limit = self.proxy_config.mirror.max_body_bytes;
+            let body = match buffer_limited(body, limit).await {
+                Ok(Buffered::Complete(body)) => {
+                    self.mirror(proxy, mirror, forwarded, vars, &parts, body.clone());
+                    full(body)
+                }
+                Ok(Buffered::TooLarge(body)) => {
+                    debug!("Not mirroring request with a body above {} bytes", limit);
+                    self.mirror_metrics.record_skipped();
+                    body
+                }
+                Err(e) => return Err(ProxyError::RequestBodyError(e.to_string())),
+            };
+            return self.attempt(proxy, forwarded, vars, &endpoints[0], Request::from_parts(parts, body)).await;
+        }
+
+        /* buffer the body so it can be replayed for every attempt */
+        let body = match body.collect().await {
+            Ok(collected) => collected.to_bytes(),
+            Err(e) => return Err(ProxyError::RequestBodyError(e.to_string())),
+        };
+        if let Some(mirror) = mirror {
+            self.mirror(proxy, mirror, forwarded, vars, &parts, body.clone());
+        }
+
+        let mut attempt = 0u32;
+        loop {
+            let endpoint = &endpoints[attempt as usize % endpoints.len()];
+            let req = Request::from_parts(parts.clone(), full(body.clone()));
+            let result = self.attempt(proxy, forwarded, vars, endpoint, req).await;
+            attempt += 1;
+
+            let next_endpoint = &endpoints[attempt as usize % endpoints.len()];
+            if attempt >= policy.max_attempts || !policy.should_retry(&result, next_endpoint != endpoint) {
+                return result;
+            }
+
+            if !self.retry_budget.try_withdraw() {
+                warn!("Retry budget exhausted, not retrying request to {}", endpoint);
+                return result;
+            }
+
+            let delay = policy.backoff.delay(attempt);
+            match &result {
+                Ok(res) => warn!("Attempt {} to {} returned {}, retrying in {}ms", attempt, endpoint, res.status(), delay.as_millis()),
+                Err(e) => warn!("Attempt {} to {} failed with {:?}, retrying in {}ms", attempt, endpoint, e, delay.as_millis()),
+            }
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Sends a copy of the request to the mirror endpoint in the background.
+    fn mirror<T: UpstreamConnect>(&self, proxy: &ReverseProxy<T>, endpoint: &Endpoint, forwarded: &ForwardedInfo, vars: &HeaderVars, parts: &request::Parts, body: Bytes) {
+        let config = &self.proxy_config.mirror;
+        if body.len() > config.max_body_bytes {
+            debug!("Not mirroring request with a body of {} bytes", body.len());
+            self.mirror_metrics.record_skipped();
+            return;
+        }
+
+        let mut req = Request::from_parts(parts.clone(), full(body));
+        self.proxy_config.rewrite.rewrite_host(&mut req, endpoint);
+        let client = proxy.clone();
+        let forwarding = self.proxy_config.forwarding.clone();
+        let forwarded = forwarded.clone();
+        let rules = self.proxy_config.headers.clone();
+        let vars = vars.with_upstream(endpoint);
+        let endpoint = endpoint.clone();
+        let metrics = self.mirror_metrics.clone();
+        let timeout = config.timeout();
+
+        metrics.record_sent();
+        tokio::spawn(async move {
+            let forward_url = endpoint.base_url();
+            let result = with_timeout(
+                timeout,
+                client.call(&forwarding, &forwarded, &rules, &vars, forward_url.as_str(), req),
+                |limit| format!("mirrored request to {} exceeded {}ms", endpoint, limit.as_millis()),
+            ).await;
+            match result {
+                Ok(res) => {
+                    let success = !res.status().is_server_error();
+                    if !success {
+                        debug!("Mirror {} responded with {}", endpoint, res.status());
+                    }
+                    /* read the body so the connection can go back to the pool */
+                    let _ = res.into_body().collect().await;
+                    metrics.record_result(success);
+                }
+                Err(e) => {
+                    debug!("Mirrored request to {} failed: {:?}", endpoint, e);
+                    metrics.record_result(false);
+                }
+            }
+        });
+    }
+
+    async fn attempt<T: UpstreamConnect>(
+        &self,
+        proxy: &ReverseProxy<T>,
+        forwarded: &ForwardedInfo,
+        vars: &HeaderVars,
+        endpoint: &Endpoint,
+        mut req: HttpRequest,
+    ) -> Result<HttpResponse, ProxyError> {
+        let rewrite = &self.proxy_config.rewrite;
+        rewrite.rewrite_host(&mut req, endpoint);
+
+        let _in_flight = self.discovery.as_ref().map(|discovery| discovery.track(endpoint));
+        let permit = match self.circuit_breakers.get(endpoint) {
+            None => None,
+            Some(breaker) => Some(breaker.acquire().await.map_err(ProxyError::CircuitOpen)?),
+        };
+
+        let forward_url = endpoint.base_url();
+        let vars = vars.with_upstream(endpoint);
+        let call = with_timeout(
+            self.proxy_config.timeouts.first_byte(),
+            proxy.call(&self.proxy_config.forwarding, forwarded, &self.proxy_config.headers, &vars, forward_url.as_str(), req),
+            |limit| format!("no response headers from {} within {}ms", endpoint, limit.as_millis()),
+        );
+        let result = with_timeout(
+            self.proxy_config.retry.per_try_timeout(),
+            call,
+            |limit| format!("attempt to {} exceeded the per-try timeout of {}ms", endpoint, limit.as_millis()),
+        ).await;
+
+        if let Some(permit) = permit {
+            permit.record(matches!(&result, Ok(res) if !res.status().is_server_error()));
+        }
+        result.map(|mut res| {
+            rewrite.rewrite_response(res.headers_mut(), endpoint, forwarded);
+            res
+        })
+    }
 }
 
 impl Handler<HttpRequest, HttpResponse> for ReverseProxyHandler
@@ -94,26 +365,49 @@ impl Handler<HttpRequest, HttpResponse> for ReverseProxyHandler
             if let Ok(req) = context.consume_request() {
                 let conf = context.attachment::<Arc<ServerConfig>>(AttachmentKey::APP_CONTEXT).unwrap();
                 let client_src = context.attachment::<SocketAddr>(AttachmentKey::CLIENT_SRC).unwrap();
-                let protocol: String = if conf.tls_enabled {
-                    "http".to_string()
-                } else {
-                    "https".to_string()
+                let (endpoints, affinity_cookie) = self.endpoints(req.headers(), conf.tls_enabled).await;
+                let forwarded = ForwardedInfo::from_request(&req, client_src.ip(), conf.tls_enabled, conf.port);
+                let vars = HeaderVars {
+                    client_ip: Some(client_src.ip()),
+                    request_id: context.attachment::<String>(AttachmentKey::REQUEST_ID).cloned(),
+                    method: req.method().to_string(),
+                    path: req.uri().path().to_string(),
+                    host: forwarded.host.clone(),
+                    params: context
+                        .attachment::<HashMap<String, String>>(AttachmentKey::ROUTE_PARAMS)
+                        .cloned()
+                        .unwrap_or_default(),
+                    upstream: None,
                 };
-                let full_url = format!(
-                    "{}://{}:{}{}",
-                    protocol,
-                    self.destination_host(),
-                    self.destination_port(),
-                    //req.uri().path(),
-                    "temp"
+                let timeouts = &self.proxy_config.timeouts;
+                let deadline = timeouts.request().map(|limit| tokio::time::Instant::now() + limit);
+                let grpc_call = is_grpc(req.headers());
+                let forward = with_timeout(
+                    timeouts.request(),
+                    self.forward(self.client(conf), &forwarded, &vars, &endpoints, req),
+                    |limit| format!("proxied request exceeded the request timeout of {}ms", limit.as_millis()),
                 );
-                let res = match proxy_client(conf)
-                    .call(client_src.ip(), full_url.as_str(), req)
-                    .await
-                {
-                    Ok(res) => res,
-                    Err(e) => panic!("proxy failed with error: {:?}", e),
+                let mut res = match forward.await {
+                    Ok(res) if grpc_call => grpc::normalize_response(res).map(|body| {
+                        TimeoutBody::new(body, timeouts.idle_body(), deadline)
+                            .with_timeout_trailers(status_trailers(GrpcStatus::DEADLINE_EXCEEDED, "upstream stream timed out"))
+                            .boxed_unsync()
+                    }),
+                    Ok(res) => res.map(|body| TimeoutBody::new(body, timeouts.idle_body(), deadline).boxed_unsync()),
+                    Err(e) => {
+                        warn!("Proxy request failed: {}", e);
+                        let res = if grpc_call {
+                            grpc::error_response(GrpcStatus::from_error(&e), &e.public_message())
+                        } else {
+                            self.proxy_config.errors.render(&e, vars.request_id.as_deref())
+                        };
+                        context.add_attachment::<ProxyError>(AttachmentKey::PROXY_ERROR, Box::new(e));
+                        res
+                    }
                 };
+                if let Some(cookie) = affinity_cookie {
+                    res.headers_mut().append(SET_COOKIE, cookie);
+                }
                 context.save_output(res);
                 return Ok(());
             }
@@ -124,25 +418,79 @@ impl Handler<HttpRequest, HttpResponse> for ReverseProxyHandler
 
 #[derive(Default, Debug, Clone, Deserialize)]
 pub struct ProxyConfig {
+    /// Host name or address of the upstream, or `unix:/path/to.sock` for a Unix domain socket.
     pub destination_host: String,
     pub destination_port: u16,
+    /// Extra endpoints serving the same content, used when a request is retried.
+    #[serde(default)]
+    pub endpoints: Vec<Endpoint>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    pub timeouts: ProxyTimeouts,
+    #[serde(default)]
+    pub client: ClientSettings,
+    #[serde(default)]
+    pub forwarding: ForwardingPolicy,
+    #[serde(default)]
+    pub rewrite: RewriteConfig,
+    /// TLS settings for https upstreams. Without them the server's `tls_client_config` is used.
+    #[serde(default)]
+    pub tls: Option<UpstreamTlsConfig>,
+    #[serde(default)]
+    pub mirror: MirrorConfig,
+    /// Weighted upstream groups, taking the place of the destination and endpoints when set.
+    #[serde(default)]
+    pub split: TrafficSplit,
+    /// Headers added, set or removed on requests to the upstream and on its responses.
+    #[serde(default)]
+    pub headers: HeaderRules,
+    #[serde(default)]
+    pub errors: ErrorResponseConfig,
+    /// Reads the cluster members from a file or DNS at runtime instead of the destination.
+    #[serde(default)]
+    pub discovery: Option<DiscoveryConfig>,
 }
 
 impl ProxyConfig {
-    pub fn load(path: &str) -> Result<Self, ()>
+    pub fn load(path: &str) -> Result<Self, ProxyConfigError>
     where
         for<'a> Self: Deserialize<'a>,
     {
-        if let Ok(mut file) = File::open(path) {
-            let mut contents = String::new();
-            if let Err(e) = file.read_to_string(&mut contents) {
-                panic!("File contains non-UTF-8 characters: {}", e)
-            }
-            if let Ok(config) = serde_json::from_str(&contents) {
-                return Ok(config);
-            }
+        let read_error = |source| ProxyConfigError::Read { path: path.to_string(), source };
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(read_error)?;
+        serde_json::from_str(&contents).map_err(|source| ProxyConfigError::Parse { path: path.to_string(), source })
+    }
+}
+
+#[derive(Debug)]
+pub enum ProxyConfigError {
+    /// The file could not be opened or is not valid UTF-8.
+    Read { path: String, source: io::Error },
+    /// The file is not valid JSON or does not describe a `ProxyConfig`, `source` has the position.
+    Parse { path: String, source: serde_json::Error },
+}
+
+impl Display for ProxyConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyConfigError::Read { path, source } => write!(f, "cannot read proxy config {}: {}", path, source),
+            ProxyConfigError::Parse { path, source } => write!(f, "invalid proxy config {}: {}", path, source),
+        }
+    }
+}
+
+impl std::error::Error for ProxyConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProxyConfigError::Read { source, .. } => Some(source),
+            ProxyConfigError::Parse { source, .. } => Some(source),
         }
-        Err(())
     }
 }
 
@@ -171,11 +519,6 @@ fn trailers_header() -> &'static HeaderName {
     TRAILERS_HEADER.get_or_init(|| HeaderName::from_static("trailers"))
 }
 
-fn x_forwarded_for_header() -> &'static HeaderName {
-    static X_FORWARDED_FOR: OnceLock<HeaderName> = OnceLock::new();
-    X_FORWARDED_FOR.get_or_init(|| HeaderName::from_static("x-forwarded-for"))
-}
-
 fn hop_headers() -> &'static [HeaderName; 9] {
     static HOP_HEADERS: OnceLock<[HeaderName; 9]> = OnceLock::new();
     HOP_HEADERS.get_or_init(|| {
@@ -201,6 +544,67 @@ pub enum ProxyError {
     ForwardHeaderError,
     UpgradeError(String),
     UpstreamError(String),
+    /// The client's request body failed while it was read for buffering.
+    RequestBodyError(String),
+    Timeout(String),
+    CircuitOpen(String),
+}
+
+impl ProxyError {
+    /// Status returned to the client when proxying fails with this error.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ProxyError::InvalidUri(_) => StatusCode::BAD_GATEWAY,
+            ProxyError::LegacyHyperError(e) if is_connect_timeout(e) => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::LegacyHyperError(_) => StatusCode::BAD_GATEWAY,
+            ProxyError::HyperError(_) => StatusCode::BAD_GATEWAY,
+            ProxyError::ForwardHeaderError => StatusCode::BAD_REQUEST,
+            ProxyError::UpgradeError(_) => StatusCode::BAD_GATEWAY,
+            ProxyError::UpstreamError(_) => StatusCode::BAD_GATEWAY,
+            ProxyError::RequestBodyError(_) => StatusCode::BAD_REQUEST,
+            ProxyError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::CircuitOpen(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// Description that is safe to return to clients, unlike `Display` it leaves out upstream
+    /// addresses and internal error details.
+    pub fn public_message(&self) -> String {
+        match self {
+            ProxyError::CircuitOpen(_) => "Upstream temporarily unavailable".to_string(),
+            _ => self.status_code().canonical_reason().unwrap_or_default().to_string(),
+        }
+    }
+}
+
+impl Display for ProxyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyError::InvalidUri(e) => write!(f, "invalid upstream uri: {}", e),
+            ProxyError::LegacyHyperError(e) => match e.source() {
+                Some(source) => write!(f, "upstream request failed: {}: {}", e, source),
+                None => write!(f, "upstream request failed: {}", e),
+            },
+            ProxyError::HyperError(e) => write!(f, "upstream connection failed: {}", e),
+            ProxyError::ForwardHeaderError => f.write_str("invalid forwarding header value"),
+            ProxyError::UpgradeError(msg) => write!(f, "connection upgrade failed: {}", msg),
+            ProxyError::UpstreamError(msg) => write!(f, "upstream error: {}", msg),
+            ProxyError::RequestBodyError(msg) => write!(f, "reading the request body failed: {}", msg),
+            ProxyError::Timeout(msg) => write!(f, "timed out: {}", msg),
+            ProxyError::CircuitOpen(msg) => write!(f, "circuit open: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProxyError::InvalidUri(e) => Some(e),
+            ProxyError::LegacyHyperError(e) => Some(e),
+            ProxyError::HyperError(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl From<LegacyError> for ProxyError {
@@ -277,13 +681,82 @@ fn remove_connection_headers(headers: &mut HeaderMap) {
     }
 }
 
-fn create_proxied_response<B>(mut response: Response<B>) -> Response<B> {
+/// Response sent to the client when a request cannot be proxied.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ErrorResponseConfig {
+    /// Body template, `${status}`, `${reason}`, `${message}`, `${detail}` and `${request_id}` are
+    /// replaced. `${message}` is safe to show to clients, `${detail}` describes the failure.
+    pub body: String,
+    /// Templates for specific status codes, taking precedence over `body`.
+    pub status_bodies: HashMap<u16, String>,
+    pub content_type: String,
+}
+
+impl Default for ErrorResponseConfig {
+    fn default() -> Self {
+        Self {
+            body: "${message}".to_string(),
+            status_bodies: HashMap::new(),
+            content_type: "text/plain".to_string(),
+        }
+    }
+}
+
+impl ErrorResponseConfig {
+    pub fn render(&self, err: &ProxyError, request_id: Option<&str>) -> HttpResponse {
+        let status = err.status_code();
+        let template = self.status_bodies.get(&status.as_u16()).unwrap_or(&self.body);
+        let body = template
+            .replace("${status}", status.as_str())
+            .replace("${reason}", status.canonical_reason().unwrap_or_default())
+            .replace("${message}", &err.public_message())
+            .replace("${detail}", &err.to_string())
+            .replace("${request_id}", request_id.unwrap_or_default());
+
+        let mut res = Response::new(full(body));
+        *res.status_mut() = status;
+        let content_type = HeaderValue::from_str(&self.content_type).unwrap_or(HeaderValue::from_static("text/plain"));
+        res.headers_mut().insert(hyper::header::CONTENT_TYPE, content_type);
+        res
+    }
+}
+
+fn create_proxied_response<B>(
+    forwarding: &ForwardingPolicy,
+    rules: &HeaderRules,
+    vars: &HeaderVars,
+    mut response: Response<B>,
+) -> Result<Response<B>, ProxyError> {
     debug!("Creating proxied response");
 
     remove_hop_headers(response.headers_mut());
     remove_connection_headers(response.headers_mut());
 
-    response
+    let version = response.version();
+    forwarding.apply_via(response.headers_mut(), version)?;
+    rules.apply_response(response.headers_mut(), vars);
+
+    Ok(response)
+}
+
+/* like create_proxied_response, but keeps the upgrade headers telling the client which
+   protocol the connection switched to */
+fn create_upgrade_response<B>(
+    forwarding: &ForwardingPolicy,
+    rules: &HeaderRules,
+    vars: &HeaderVars,
+    response: Response<B>,
+) -> Result<Response<B>, ProxyError> {
+    let upgrade_type = response.headers().get(upgrade_header()).cloned();
+    let mut response = create_proxied_response(forwarding, rules, vars, response)?;
+    if let Some(value) = upgrade_type {
+        response.headers_mut().insert(upgrade_header(), value);
+        response
+            .headers_mut()
+            .insert(connection_header(), HeaderValue::from_static("upgrade"));
+    }
+    Ok(response)
 }
 
 fn create_forward_uri<B>(forward_url: &str, req: &Request<B>) -> String {
@@ -363,7 +836,10 @@ fn create_forward_uri<B>(forward_url: &str, req: &Request<B>) -> String {
 }
 
 fn create_proxied_request(
-    client_ip: IpAddr,
+    forwarding: &ForwardingPolicy,
+    forwarded: &ForwardedInfo,
+    rules: &HeaderRules,
+    vars: &HeaderVars,
     mut request: Request<HttpBody>,
     upgrade_type: Option<&String>,
 ) -> Result<Request<HttpBody>, ProxyError> {
@@ -404,85 +880,69 @@ fn create_proxied_request(
     }
 
     // Add forwarding information in the headers
-    match request.headers_mut().entry(x_forwarded_for_header()) {
-        hyper::header::Entry::Vacant(entry) => {
-            debug!("X-Forwarded-for header was vacant");
-            entry.insert(client_ip.to_string().parse()?);
-        }
-
-        hyper::header::Entry::Occupied(entry) => {
-            debug!("X-Forwarded-for header was occupied");
-            let client_ip_str = client_ip.to_string();
-            let mut addr =
-                String::with_capacity(entry.get().as_bytes().len() + 2 + client_ip_str.len());
-
-            addr.push_str(std::str::from_utf8(entry.get().as_bytes()).unwrap());
-            addr.push(',');
-            addr.push(' ');
-            addr.push_str(&client_ip_str);
-        }
-    }
+    let version = request.version();
+    forwarding.apply(request.headers_mut(), forwarded, version)?;
+    rules.apply_request(request.headers_mut(), vars);
 
     debug!("Created proxied request");
 
     Ok(request)
 }
 
-fn get_upstream_addr(forward_uri: &str) -> Result<SocketAddr, ProxyError> {
-    let forward_uri: Uri = forward_uri.parse().map_err(|e| {
-        ProxyError::UpstreamError(format!("parsing forward_uri as a Uri: {e}").to_string())
-    })?;
-    let host = forward_uri.host().ok_or(ProxyError::UpstreamError(
-        "forward_uri has no host".to_string(),
-    ))?;
-    let port = forward_uri.port_u16().ok_or(ProxyError::UpstreamError(
-        "forward_uri has no port".to_string(),
-    ))?;
-    format!("{host}:{port}").parse().map_err(|_| {
-        ProxyError::UpstreamError("forward_uri host must be an IP address".to_string())
-    })
-}
-
-pub async fn call<T: Connect + Clone + Send + Sync + 'static>(
-    client_ip: IpAddr,
+pub async fn call<T: UpstreamConnect>(
+    forwarding: &ForwardingPolicy,
+    forwarded: &ForwardedInfo,
+    rules: &HeaderRules,
+    vars: &HeaderVars,
     forward_uri: &str,
     request: Request<HttpBody>,
-    client: &Client<T, HttpBody>,
+    proxy: &ReverseProxy<T>,
 ) -> Result<Response<HttpBody>, ProxyError> {
     debug!(
         "Received proxy call from {} to {}, client: {}",
-        request.uri().to_string(),
+        request.uri(),
         forward_uri,
-        client_ip
+        forwarded.client_ip
     );
 
     let request_upgrade_type = get_upgrade_type(request.headers());
 
-    let mut request = create_proxied_request(client_ip, request, request_upgrade_type.as_ref())?;
+    let mut request = create_proxied_request(forwarding, forwarded, rules, vars, request, request_upgrade_type.as_ref())?;
 
     if request_upgrade_type.is_none() {
         let request_uri: Uri = create_forward_uri(forward_uri, &request).parse()?;
         *request.uri_mut() = request_uri.clone();
 
-        let response = client.request(request).await?;
+        let response = proxy.client.request(request).await?;
 
         debug!("Responding to call with response");
-        return Ok(create_proxied_response(
-            response.map(|body| body.map_err(|_| todo!()).boxed_unsync()),
-        ));
+        let error_trailers = status_trailers(GrpcStatus::UNAVAILABLE, "upstream stream failed");
+        let grpc_response = is_grpc(response.headers());
+        return create_proxied_response(
+            forwarding,
+            rules,
+            vars,
+            response.map(|body| {
+                if grpc_response {
+                    ErrorTrailersBody::new(body, error_trailers).boxed_unsync()
+                } else {
+                    body.map_err(BoxError::from).boxed_unsync()
+                }
+            }),
+        );
     }
 
-    let upstream_addr = get_upstream_addr(forward_uri)?;
+    let upstream_uri: Uri = forward_uri.parse()?;
     let (request_parts, request_body) = request.into_parts();
     let upstream_request = Request::from_parts(request_parts.clone(), Empty::<Bytes>::new());
     let mut downstream_request = Request::from_parts(request_parts, request_body);
 
-    let (mut upstream_conn, downstream_response) = {
-        let conn = TokioIo::new(
-            TcpStream::connect(upstream_addr)
-                .await
-                .map_err(|e| ProxyError::UpstreamError(e.to_string()))?,
-        );
+    let (upstream_conn, downstream_response) = {
+        let conn = proxy
+            .connector
+            .connect_upstream(upstream_uri)
+            .await
+            .map_err(|e| ProxyError::UpstreamError(format!("connecting to {}: {}", forward_uri, e)))?;
         let (mut sender, conn) = conn::http1::handshake(conn).await?;
 
         tokio::task::spawn(async move {
@@ -501,15 +961,18 @@ pub async fn call<T: Connect + Clone + Send + Sync + 'static>(
 
         let (response_parts, response_body) = response.into_parts();
         let upstream_response = Response::from_parts(response_parts.clone(), response_body);
-        let downstream_response = Response::from_parts(response_parts, Empty::new());
+        let downstream_response =
+            create_upgrade_response(forwarding, rules, vars, Response::from_parts(response_parts, empty()))?;
         (
             TokioIo::new(hyper::upgrade::on(upstream_response).await?),
             downstream_response,
         )
     };
 
+    let idle_timeout = proxy.tunnel_idle_timeout;
+    let metrics = proxy.tunnel_metrics.clone();
     tokio::task::spawn(async move {
-        let mut downstream_conn = match hyper::upgrade::on(&mut downstream_request).await {
+        let downstream_conn = match hyper::upgrade::on(&mut downstream_request).await {
             Ok(upgraded) => TokioIo::new(upgraded),
             Err(e) => {
                 warn!("Failed to upgrade request: {e}");
@@ -517,30 +980,154 @@ pub async fn call<T: Connect + Clone + Send + Sync + 'static>(
             }
         };
 
-        if let Err(e) = copy_bidirectional(&mut downstream_conn, &mut upstream_conn).await {
-            warn!("Bidirectional copy failed: {e}");
-        }
+        run_tunnel(downstream_conn, upstream_conn, idle_timeout, &metrics).await;
     });
 
-    Ok(downstream_response.map(|body| body.map_err(|_| todo!()).boxed_unsync()))
+    Ok(downstream_response)
 }
 
 #[derive(Debug, Clone)]
-pub struct ReverseProxy<T: Connect + Clone + Send + Sync + 'static> {
+pub struct ReverseProxy<T: UpstreamConnect> {
     client: Client<T, HttpBody>,
+    connector: T,
+    tunnel_idle_timeout: Option<Duration>,
+    tunnel_metrics: Arc<TunnelMetrics>,
 }
 
-impl<T: Connect + Clone + Send + Sync + 'static> ReverseProxy<T> {
-    pub fn new(client: Client<T, HttpBody>) -> Self {
-        Self { client }
+impl<T: UpstreamConnect> ReverseProxy<T> {
+    /// `connector` opens upgraded connections, which bypass the pooled `client`.
+    pub fn new(client: Client<T, HttpBody>, connector: T) -> Self {
+        Self {
+            client,
+            connector,
+            tunnel_idle_timeout: None,
+            tunnel_metrics: Arc::new(TunnelMetrics::default()),
+        }
+    }
+
+    pub fn tunnel_idle_timeout(mut self, value: Option<Duration>) -> Self {
+        self.tunnel_idle_timeout = value;
+        self
+    }
+
+    pub fn tunnel_stats(&self) -> TunnelStats {
+        self.tunnel_metrics.snapshot()
     }
 
     pub async fn call(
         &self,
-        client_ip: IpAddr,
+        forwarding: &ForwardingPolicy,
+        forwarded: &ForwardedInfo,
+        rules: &HeaderRules,
+        vars: &HeaderVars,
         forward_uri: &str,
         request: Request<HttpBody>,
     ) -> Result<Response<HttpBody>, ProxyError> {
-        call::<T>(client_ip, forward_uri, request, &self.client).await
+        call::<T>(forwarding, forwarded, rules, vars, forward_uri, request, self).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::proxy::headers::{HeaderAction, HeaderRule};
+    use crate::server::HandshakeFailure;
+    use crate::tls_config::{TlsConfig, TlsVersion};
+
+    #[test]
+    fn test_load_reports_the_problem() {
+        let missing = ProxyConfig::load("does/not/exist.json").unwrap_err();
+        assert!(matches!(missing, ProxyConfigError::Read { .. }));
+
+        let path = std::env::temp_dir().join(format!("hyper-line-proxy-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"destination_host": "backend", "destination_port": "x"}"#).unwrap();
+        let invalid = ProxyConfig::load(path.to_str().unwrap()).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(invalid.to_string().contains("line 1 column"), "{}", invalid);
+    }
+
+    #[test]
+    fn test_client_waits_for_server_tls_settings() {
+        let handler = ReverseProxyHandler::try_new(ProxyConfig::default()).unwrap();
+        assert!(handler.client.get().is_none(), "Should use the server's TLS client settings.");
+
+        let own = ProxyConfig { tls: Some(UpstreamTlsConfig::default()), ..ProxyConfig::default() };
+        assert!(ReverseProxyHandler::try_new(own).unwrap().client.get().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_server_tls_settings_reach_upstream_connections() {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key = rustls::pki_types::PrivateKeyDer::try_from(generated.signing_key.serialize_der()).unwrap();
+        let upstream = rustls::ServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
+            .with_no_client_auth()
+            .with_single_cert(vec![generated.cert.der().clone()], key)
+            .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accepted = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            tokio_rustls::TlsAcceptor::from(Arc::new(upstream)).accept(tcp).await.map(|_| ())
+        });
+
+        /* the server only allows TLS 1.2 towards upstreams, which this one does not speak */
+        let tls = TlsConfig { max_version: TlsVersion::Tls12, ..TlsConfig::default() };
+        let server = ServerConfig { tls_client_config: Some(tls.client_config().unwrap()), ..ServerConfig::default() };
+        let handler = ReverseProxyHandler::try_new(ProxyConfig::default()).unwrap();
+        let url = format!("https://localhost:{}", port).parse().unwrap();
+        assert!(handler.client(&server).connector.connect_upstream(url).await.is_err());
+        let err = accepted.await.unwrap().unwrap_err();
+        assert_eq!(HandshakeFailure::from_error(&err), HandshakeFailure::ProtocolVersion, "{}", err);
+    }
+
+    #[test]
+    fn test_invalid_server_name_is_an_error() {
+        let tls = UpstreamTlsConfig { server_name: Some("not a name!".to_string()), ..UpstreamTlsConfig::default() };
+        let config = ProxyConfig { tls: Some(tls), ..ProxyConfig::default() };
+        assert!(ReverseProxyHandler::try_new(config.clone()).is_err());
+        assert!(ReverseProxyHandler::with_tls_client_config(config, default_tls_client_config()).is_err());
+    }
+
+    #[test]
+    fn test_upgrade_response_is_post_processed() {
+        let rules = HeaderRules {
+            request: vec![],
+            response: vec![HeaderRule {
+                action: HeaderAction::Set,
+                name: "x-request-id".to_string(),
+                value: "${request_id}".to_string(),
+            }],
+        };
+        let vars = HeaderVars { request_id: Some("r1".to_string()), ..HeaderVars::default() };
+        let upstream = Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header("upgrade", "websocket")
+            .header("connection", "Upgrade")
+            .header("keep-alive", "timeout=5")
+            .body(())
+            .unwrap();
+
+        let res = create_upgrade_response(&ForwardingPolicy::default(), &rules, &vars, upstream).unwrap();
+        assert_eq!(res.headers()["upgrade"], "websocket");
+        assert_eq!(res.headers()["connection"], "upgrade");
+        assert!(res.headers().get("keep-alive").is_none());
+        assert_eq!(res.headers()["via"], "1.1 hyper-line");
+        assert_eq!(res.headers()["x-request-id"], "r1");
+    }
+
+    #[tokio::test]
+    async fn test_error_response_uses_status_template() {
+        let config = ErrorResponseConfig {
+            status_bodies: HashMap::from([(504, "${status} after ${detail} [${request_id}]".to_string())]),
+            ..ErrorResponseConfig::default()
+        };
+        let res = config.render(&ProxyError::Timeout("no response in 10ms".to_string()), Some("r1"));
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "504 after timed out: no response in 10ms [r1]");
+
+        let res = config.render(&ProxyError::UpstreamError("refused".to_string()), None);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "Bad Gateway");
     }
 }
@@ -1,19 +1,23 @@
 #![allow(dead_code)]
 pub mod handler;
+pub mod body;
 mod service;
 pub mod exchange;
 pub mod cert_manager;
+pub mod tls_config;
 pub mod logger;
 pub mod server;
+pub mod proxy;
 
 
-use std::convert::Infallible;
 use std::sync::Arc;
 use http_body_util::combinators::UnsyncBoxBody;
 use hyper::body::Bytes;
 use crate::handler::Handler;
 
-pub type HttpBody = UnsyncBoxBody<Bytes, Infallible>;
+/// Error ending a body stream early, e.g. a client resetting the stream or a stalled upstream.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+pub type HttpBody = UnsyncBoxBody<Bytes, BoxError>;
 pub type HttpRequest = http::Request<HttpBody>;
 pub type HttpResponse = http::Response<HttpBody>;
 pub type HttpHandler = Arc<dyn Handler<HttpRequest, HttpResponse> + Sync + Send + 'static>;
@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use crate::proxy::endpoint::Endpoint;
+use crate::proxy::window::RollingWindow;
+
+/// Circuit breaker settings, applied to every upstream endpoint of a handler separately.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    pub enabled: bool,
+    /// Share of failed calls in the window that opens the circuit.
+    pub failure_rate_threshold: f64,
+    /// Share of slow calls in the window that opens the circuit.
+    pub slow_call_rate_threshold: f64,
+    pub slow_call_duration_ms: u64,
+    /// Calls needed in the window before the rates are evaluated.
+    pub minimum_calls: u32,
+    pub window_secs: u64,
+    /// How long the circuit stays open before trial calls are let through.
+    pub open_duration_ms: u64,
+    /// Trial calls allowed while half-open, all of them must succeed to close the circuit.
+    pub half_open_max_calls: u32,
+    pub max_concurrent_requests: Option<u32>,
+    /// Requests allowed to wait for a concurrency slot, the rest are rejected.
+    pub max_pending_requests: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            failure_rate_threshold: 0.5,
+            slow_call_rate_threshold: 1.0,
+            slow_call_duration_ms: 5000,
+            minimum_calls: 20,
+            window_secs: 10,
+            open_duration_ms: 30000,
+            half_open_max_calls: 5,
+            max_concurrent_requests: None,
+            max_pending_requests: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl Display for CircuitState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CircuitState::Closed => f.write_str("closed"),
+            CircuitState::Open => f.write_str("open"),
+            CircuitState::HalfOpen => f.write_str("half-open"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CallBucket {
+    calls: u32,
+    failures: u32,
+    slow: u32,
+}
+
+#[derive(Debug)]
+struct BreakerInner {
+    state: CircuitState,
+    opened_at: Instant,
+    half_open_calls: u32,
+    half_open_successes: u32,
+    window: RollingWindow<CallBucket>,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: String,
+    config: CircuitBreakerConfig,
+    inner: Mutex<BreakerInner>,
+    limiter: Option<Arc<Semaphore>>,
+    pending: AtomicU32,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, config: CircuitBreakerConfig) -> Self {
+        let window_secs = config.window_secs.max(1);
+        Self {
+            name: name.to_string(),
+            limiter: config.max_concurrent_requests.map(|max| Arc::new(Semaphore::new(max as usize))),
+            inner: Mutex::new(BreakerInner {
+                state: CircuitState::Closed,
+                opened_at: Instant::now(),
+                half_open_calls: 0,
+                half_open_successes: 0,
+                window: RollingWindow::new(Duration::from_secs(window_secs), window_secs as usize),
+            }),
+            pending: AtomicU32::new(0),
+            config,
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let mut inner = self.inner.lock().unwrap();
+        self.refresh(&mut inner);
+        inner.state
+    }
+
+    /// Checks the circuit and waits for a concurrency slot, the returned permit reports the
+    /// outcome of the call. Errors describe why the call was rejected.
+    pub async fn acquire(self: &Arc<Self>) -> Result<CallPermit, String> {
+        /* an open circuit rejects before a concurrency slot is taken */
+        self.admit(false)?;
+
+        let slot = match &self.limiter {
+            None => None,
+            Some(limiter) => match limiter.clone().try_acquire_owned() {
+                Ok(slot) => Some(slot),
+                Err(_) => {
+                    if self.pending.fetch_add(1, Ordering::SeqCst) >= self.config.max_pending_requests {
+                        self.pending.fetch_sub(1, Ordering::SeqCst);
+                        return Err(format!("upstream {} has too many pending requests", self.name));
+                    }
+                    let slot = limiter.clone().acquire_owned().await;
+                    self.pending.fetch_sub(1, Ordering::SeqCst);
+                    slot.ok()
+                }
+            },
+        };
+
+        /* the circuit may have opened while waiting for the slot */
+        self.admit(true)?;
+
+        Ok(CallPermit {
+            breaker: self.clone(),
+            start: Instant::now(),
+            recorded: false,
+            _slot: slot,
+        })
+    }
+
+    /* fails when the circuit rejects calls, `take` counts the call as a half-open trial */
+    fn admit(&self, take: bool) -> Result<(), String> {
+        let mut inner = self.inner.lock().unwrap();
+        self.refresh(&mut inner);
+        match inner.state {
+            CircuitState::Open => Err(format!("circuit breaker for upstream {} is open", self.name)),
+            CircuitState::HalfOpen if inner.half_open_calls >= self.config.half_open_max_calls => {
+                Err(format!("circuit breaker for upstream {} is half-open and busy", self.name))
+            }
+            CircuitState::HalfOpen => {
+                if take {
+                    inner.half_open_calls += 1;
+                }
+                Ok(())
+            }
+            CircuitState::Closed => Ok(()),
+        }
+    }
+
+    /* move an open circuit to half-open once the open duration has passed */
+    fn refresh(&self, inner: &mut BreakerInner) {
+        if inner.state == CircuitState::Open
+            && inner.opened_at.elapsed() >= Duration::from_millis(self.config.open_duration_ms)
+        {
+            info!("Circuit breaker for upstream {} is half-open", self.name);
+            inner.state = CircuitState::HalfOpen;
+            inner.half_open_calls = 0;
+            inner.half_open_successes = 0;
+        }
+    }
+
+    fn trip(&self, inner: &mut BreakerInner) {
+        warn!("Circuit breaker for upstream {} is open", self.name);
+        inner.state = CircuitState::Open;
+        inner.opened_at = Instant::now();
+    }
+
+    fn on_result(&self, success: bool, elapsed: Duration) {
+        let slow = elapsed >= Duration::from_millis(self.config.slow_call_duration_ms);
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            CircuitState::Open => {}
+            CircuitState::HalfOpen => {
+                if !success || slow {
+                    self.trip(&mut inner);
+                } else {
+                    inner.half_open_successes += 1;
+                    if inner.half_open_successes >= self.config.half_open_max_calls {
+                        info!("Circuit breaker for upstream {} is closed", self.name);
+                        inner.state = CircuitState::Closed;
+                        inner.window.reset();
+                    }
+                }
+            }
+            CircuitState::Closed => {
+                let bucket = inner.window.current(now);
+                bucket.calls += 1;
+                bucket.failures += !success as u32;
+                bucket.slow += slow as u32;
+
+                let totals = inner.window.live(now).fold(CallBucket::default(), |acc, b| CallBucket {
+                    calls: acc.calls + b.calls,
+                    failures: acc.failures + b.failures,
+                    slow: acc.slow + b.slow,
+                });
+                if totals.calls >= self.config.minimum_calls.max(1) {
+                    let failure_rate = totals.failures as f64 / totals.calls as f64;
+                    let slow_rate = totals.slow as f64 / totals.calls as f64;
+                    if failure_rate >= self.config.failure_rate_threshold
+                        || slow_rate >= self.config.slow_call_rate_threshold
+                    {
+                        self.trip(&mut inner);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Held for the duration of one upstream call. Dropping it without recording a result, e.g.
+/// because the call was cancelled by a timeout, counts as a failure.
+pub struct CallPermit {
+    breaker: Arc<CircuitBreaker>,
+    start: Instant,
+    recorded: bool,
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+impl CallPermit {
+    pub fn record(mut self, success: bool) {
+        self.recorded = true;
+        self.breaker.on_result(success, self.start.elapsed());
+    }
+}
+
+impl Drop for CallPermit {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.breaker.on_result(false, self.start.elapsed());
+        }
+    }
+}
+
+/// Lazily created circuit breakers, one per upstream endpoint.
+#[derive(Debug, Default)]
+pub struct CircuitBreakers {
+    config: CircuitBreakerConfig,
+    breakers: RwLock<HashMap<Endpoint, Arc<CircuitBreaker>>>,
+    /* members of the discovered cluster as of the last `update_discovered` */
+    discovered: Mutex<Arc<Vec<Endpoint>>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            breakers: RwLock::new(HashMap::new()),
+            discovered: Mutex::new(Arc::new(vec![])),
+        }
+    }
+
+    pub fn get(&self, endpoint: &Endpoint) -> Option<Arc<CircuitBreaker>> {
+        if !self.config.enabled {
+            return None;
+        }
+        if let Some(breaker) = self.breakers.read().unwrap().get(endpoint) {
+            return Some(breaker.clone());
+        }
+        let mut breakers = self.breakers.write().unwrap();
+        let breaker = breakers
+            .entry(endpoint.clone())
+            .or_insert_with(|| Arc::new(CircuitBreaker::new(&endpoint.to_string(), self.config.clone())));
+        Some(breaker.clone())
+    }
+
+    /// Drops the breakers of endpoints that have left the discovered cluster since the last
+    /// call, `members` being its current members. Breakers of other endpoints are kept.
+    pub fn update_discovered(&self, members: &Arc<Vec<Endpoint>>) {
+        let mut discovered = self.discovered.lock().unwrap();
+        /* discovery replaces its member list only when membership changes */
+        if Arc::ptr_eq(&discovered, members) {
+            return;
+        }
+        let removed: Vec<_> = discovered.iter().filter(|endpoint| !members.contains(endpoint)).collect();
+        if !removed.is_empty() {
+            let mut breakers = self.breakers.write().unwrap();
+            for endpoint in removed {
+                breakers.remove(endpoint);
+            }
+        }
+        *discovered = members.clone();
+    }
+
+    /// Current state of every upstream that has been called so far.
+    pub fn states(&self) -> Vec<(Endpoint, CircuitState)> {
+        self.breakers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(endpoint, breaker)| (endpoint.clone(), breaker.state()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config() -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            enabled: true,
+            minimum_calls: 2,
+            open_duration_ms: 20,
+            half_open_max_calls: 1,
+            ..CircuitBreakerConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_circuit_opens_and_recovers() {
+        let breaker = Arc::new(CircuitBreaker::new("test", config()));
+        breaker.acquire().await.unwrap().record(false);
+        breaker.acquire().await.unwrap().record(false);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(breaker.acquire().await.is_err(), "Should reject calls while open.");
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        breaker.acquire().await.unwrap().record(true);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_pending_requests_are_limited() {
+        let breaker = Arc::new(CircuitBreaker::new("test", CircuitBreakerConfig {
+            max_concurrent_requests: Some(1),
+            ..config()
+        }));
+        let _held = breaker.acquire().await.unwrap();
+        assert!(breaker.acquire().await.is_err(), "Should reject when no request may wait.");
+    }
+
+    #[tokio::test]
+    async fn test_open_circuit_takes_no_slot() {
+        let breaker = Arc::new(CircuitBreaker::new("test", CircuitBreakerConfig {
+            max_concurrent_requests: Some(1),
+            ..config()
+        }));
+        breaker.acquire().await.unwrap().record(false);
+        breaker.acquire().await.unwrap().record(false);
+        assert!(breaker.acquire().await.is_err());
+        let limiter = breaker.limiter.as_ref().unwrap();
+        assert_eq!(limiter.available_permits(), 1, "Should not hold a slot for a rejected call.");
+    }
+
+    #[test]
+    fn test_breakers_of_removed_members_are_dropped() {
+        let breakers = CircuitBreakers::new(config());
+        let a: Endpoint = "http://a:80".parse().unwrap();
+        let b: Endpoint = "http://b:80".parse().unwrap();
+        let configured: Endpoint = "http://c:80".parse().unwrap();
+        breakers.update_discovered(&Arc::new(vec![a.clone(), b.clone()]));
+        for endpoint in [&a, &b, &configured] {
+            breakers.get(endpoint);
+        }
+
+        breakers.update_discovered(&Arc::new(vec![b.clone()]));
+        let mut remaining: Vec<_> = breakers.states().into_iter().map(|(endpoint, _)| endpoint).collect();
+        remaining.sort_by_key(|endpoint| endpoint.to_string());
+        assert_eq!(remaining, vec![b, configured]);
+    }
+}
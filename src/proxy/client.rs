@@ -0,0 +1,242 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use hyper::rt::{Read, ReadBufCursor, Write};
+use hyper::Uri;
+use hyper_rustls::builderstates::WantsProtocols1;
+use hyper_rustls::{ConfigBuilderExt, FixedServerNameResolver, HttpsConnector, HttpsConnectorBuilder, MaybeHttpsStream};
+use hyper_util::client::legacy::connect::{Connect, Connected, Connection, HttpConnector};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use tokio::net::TcpStream;
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig as TlsClientConfig;
+use serde::Deserialize;
+use tower_service::Service;
+use crate::handler::reverse_proxy_handler::ReverseProxy;
+use crate::proxy::endpoint::decode_socket_path;
+use crate::proxy::timeout::ProxyTimeouts;
+use crate::service::ServiceExecutor;
+use crate::{BoxError, HttpBody};
+
+/// Connects to `http` and `https` upstreams over TCP and to `unix` ones, see
+/// `Endpoint::base_url`, over a Unix domain socket.
+#[derive(Debug, Clone)]
+pub struct ProxyConnector {
+    tcp: HttpsConnector<HttpConnector>,
+}
+
+impl ProxyConnector {
+    pub fn new(tcp: HttpsConnector<HttpConnector>) -> Self {
+        Self { tcp }
+    }
+}
+
+pub enum UpstreamIo {
+    Tcp(Box<MaybeHttpsStream<TokioIo<TcpStream>>>),
+    #[cfg(unix)]
+    Unix(TokioIo<tokio::net::UnixStream>),
+}
+
+impl Service<Uri> for ProxyConnector {
+    type Response = UpstreamIo;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<UpstreamIo, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.tcp.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        if uri.scheme_str() == Some("unix") {
+            return Box::pin(connect_unix(uri));
+        }
+        let connecting = self.tcp.call(uri);
+        Box::pin(async move { connecting.await.map(|stream| UpstreamIo::Tcp(Box::new(stream))) })
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(uri: Uri) -> Result<UpstreamIo, BoxError> {
+    let path = decode_socket_path(&uri).ok_or_else(|| format!("invalid unix socket url {}", uri))?;
+    let stream = tokio::net::UnixStream::connect(&path)
+        .await
+        .map_err(|e| format!("connecting to unix:{}: {}", path.display(), e))?;
+    Ok(UpstreamIo::Unix(TokioIo::new(stream)))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(uri: Uri) -> Result<UpstreamIo, BoxError> {
+    Err(format!("unix socket upstreams are not supported on this platform: {}", uri).into())
+}
+
+impl Connection for UpstreamIo {
+    fn connected(&self) -> Connected {
+        match self {
+            UpstreamIo::Tcp(stream) => stream.connected(),
+            #[cfg(unix)]
+            UpstreamIo::Unix(_) => Connected::new(),
+        }
+    }
+}
+
+impl Read for UpstreamIo {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: ReadBufCursor<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamIo::Tcp(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+            #[cfg(unix)]
+            UpstreamIo::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl Write for UpstreamIo {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamIo::Tcp(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+            #[cfg(unix)]
+            UpstreamIo::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamIo::Tcp(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+            #[cfg(unix)]
+            UpstreamIo::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamIo::Tcp(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+            #[cfg(unix)]
+            UpstreamIo::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Connector usable both by the pooled client and for opening upgraded connections directly,
+/// so that both resolve hostnames and use TLS the same way.
+pub trait UpstreamConnect: Connect + Clone + Send + Sync + 'static {
+    type Io: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static;
+
+    fn connect_upstream(&self, uri: Uri) -> Pin<Box<dyn Future<Output = Result<Self::Io, BoxError>> + Send>>;
+}
+
+impl<S> UpstreamConnect for S
+where
+    S: Connect + Service<Uri> + Clone + Send + Sync + 'static,
+    S::Response: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Io = S::Response;
+
+    fn connect_upstream(&self, uri: Uri) -> Pin<Box<dyn Future<Output = Result<Self::Io, BoxError>> + Send>> {
+        let mut connector = self.clone();
+        Box::pin(async move {
+            std::future::poll_fn(|cx| connector.poll_ready(cx)).await.map_err(Into::into)?;
+            connector.call(uri).await.map_err(Into::into)
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UpstreamHttpVersion {
+    /// HTTP/1.1, or HTTP/2 when negotiated through ALPN.
+    #[serde(alias = "auto")]
+    #[default]
+    Auto,
+
+    #[serde(alias = "http1")]
+    Http1,
+
+    /// HTTP/2 with prior knowledge, also over plain text connections. Plain text gRPC upstreams
+    /// need this, since without TLS there is no ALPN to negotiate HTTP/2.
+    #[serde(alias = "http2")]
+    Http2,
+}
+
+/// Connection settings of the client a `ReverseProxyHandler` uses to reach its upstream.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ClientSettings {
+    pub pool_max_idle_per_host: usize,
+    pub pool_idle_timeout_ms: Option<u64>,
+    pub http_version: UpstreamHttpVersion,
+    /// Interval of TCP keep-alive probes on upstream connections.
+    pub tcp_keepalive_ms: Option<u64>,
+    pub tcp_nodelay: bool,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        Self {
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout_ms: Some(3000),
+            http_version: UpstreamHttpVersion::Auto,
+            tcp_keepalive_ms: None,
+            tcp_nodelay: true,
+        }
+    }
+}
+
+/// Default TLS settings for upstream connections, trusting the webpki root certificates.
+pub fn default_tls_client_config() -> TlsClientConfig {
+    TlsClientConfig::builder()
+        .with_webpki_roots()
+        .with_no_client_auth()
+}
+
+/// Builds the client of a handler. `server_name`, when set, replaces the url host in SNI and
+/// in the upstream certificate check.
+pub fn build_client(
+    settings: &ClientSettings,
+    timeouts: &ProxyTimeouts,
+    tls_config: TlsClientConfig,
+    server_name: Option<ServerName<'static>>,
+) -> ReverseProxy<ProxyConnector> {
+    let mut http_connector = HttpConnector::new();
+    http_connector.enforce_http(false);
+    http_connector.set_connect_timeout(timeouts.connect());
+    http_connector.set_nodelay(settings.tcp_nodelay);
+    http_connector.set_keepalive(settings.tcp_keepalive_ms.map(Duration::from_millis));
+
+    let resolve = |builder: HttpsConnectorBuilder<WantsProtocols1>| match &server_name {
+        Some(name) => builder.with_server_name_resolver(FixedServerNameResolver::new(name.clone())),
+        None => builder,
+    };
+
+    /* upgrades are negotiated over HTTP/1.1, so that connector only offers http/1.1 through ALPN */
+    let upgrade_connector = resolve(
+        HttpsConnector::<HttpConnector>::builder()
+            .with_tls_config(tls_config.clone())
+            .https_or_http(),
+    )
+        .enable_http1()
+        .wrap_connector(http_connector.clone());
+    let upgrade_connector = ProxyConnector::new(upgrade_connector);
+
+    let builder = resolve(
+        HttpsConnector::<HttpConnector>::builder()
+            .with_tls_config(tls_config)
+            .https_or_http(),
+    );
+    let connector = match settings.http_version {
+        UpstreamHttpVersion::Auto => builder.enable_http1().enable_http2().wrap_connector(http_connector),
+        UpstreamHttpVersion::Http1 => builder.enable_http1().wrap_connector(http_connector),
+        UpstreamHttpVersion::Http2 => builder.enable_http2().wrap_connector(http_connector),
+    };
+
+    let mut client_builder = hyper_util::client::legacy::Builder::new(ServiceExecutor);
+    client_builder
+        .pool_max_idle_per_host(settings.pool_max_idle_per_host)
+        .pool_idle_timeout(settings.pool_idle_timeout_ms.map(Duration::from_millis))
+        .pool_timer(TokioTimer::new())
+        .http2_only(settings.http_version == UpstreamHttpVersion::Http2);
+
+    ReverseProxy::new(client_builder.build::<_, HttpBody>(ProxyConnector::new(connector)), upgrade_connector)
+        .tunnel_idle_timeout(timeouts.tunnel_idle())
+}
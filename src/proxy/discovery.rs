@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::Duration;
+use hickory_resolver::proto::rr::RData;
+use hickory_resolver::TokioResolver;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
+use crate::proxy::endpoint::Endpoint;
+
+/// Sources the endpoints of a cluster are read from at runtime. Endpoints found by all
+/// configured sources are used together.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    /// JSON file listing endpoints, either `["http://10.0.0.1:8080", ...]` or
+    /// `{"endpoints": [...]}`. It is read again on every refresh.
+    pub file: Option<PathBuf>,
+    /// `host:port` whose A and AAAA records are the endpoints.
+    pub dns: Option<String>,
+    /// SRV record name, e.g. `_http._tcp.backend.local`. Targets of the lowest priority are used.
+    pub srv: Option<String>,
+    /// Whether endpoints found through DNS use https.
+    pub tls: bool,
+    pub refresh_ms: u64,
+    /// How long removed endpoints are tracked while their requests complete.
+    pub drain_timeout_ms: u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            dns: None,
+            srv: None,
+            tls: false,
+            refresh_ms: 5000,
+            drain_timeout_ms: 30000,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EndpointFile {
+    List(Vec<Endpoint>),
+    Object { endpoints: Vec<Endpoint> },
+}
+
+/// Current members of a discovered cluster. Removed endpoints get no new requests and are
+/// kept as draining until their in-flight requests finish or the drain timeout passes.
+pub struct ServiceDiscovery {
+    config: DiscoveryConfig,
+    members: RwLock<Arc<Vec<Endpoint>>>,
+    in_flight: Mutex<HashMap<Endpoint, usize>>,
+    draining: Mutex<HashMap<Endpoint, Instant>>,
+    resolver: OnceCell<Option<TokioResolver>>,
+    started: OnceCell<()>,
+}
+
+impl std::fmt::Debug for ServiceDiscovery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServiceDiscovery")
+            .field("config", &self.config)
+            .field("members", &self.members())
+            .finish()
+    }
+}
+
+/// Marks a request as in flight to an endpoint until dropped.
+pub struct InFlight {
+    discovery: Arc<ServiceDiscovery>,
+    endpoint: Endpoint,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.discovery.finish(&self.endpoint);
+    }
+}
+
+impl ServiceDiscovery {
+    pub fn new(config: DiscoveryConfig) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            members: RwLock::new(Arc::new(vec![])),
+            in_flight: Mutex::new(HashMap::new()),
+            draining: Mutex::new(HashMap::new()),
+            resolver: OnceCell::new(),
+            started: OnceCell::new(),
+        })
+    }
+
+    /// Current members, loading them and starting the refresh task on first use.
+    pub async fn endpoints(self: &Arc<Self>) -> Arc<Vec<Endpoint>> {
+        self.started
+            .get_or_init(|| async {
+                if let Err(e) = self.refresh().await {
+                    warn!("Initial service discovery failed: {}", e);
+                }
+                self.spawn_refresh();
+            })
+            .await;
+        self.members.read().unwrap().clone()
+    }
+
+    pub fn members(&self) -> Vec<Endpoint> {
+        self.members.read().unwrap().to_vec()
+    }
+
+    pub fn draining(&self) -> Vec<Endpoint> {
+        self.draining.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn track(self: &Arc<Self>, endpoint: &Endpoint) -> InFlight {
+        *self.in_flight.lock().unwrap().entry(endpoint.clone()).or_default() += 1;
+        InFlight {
+            discovery: self.clone(),
+            endpoint: endpoint.clone(),
+        }
+    }
+
+    /// Reads every source and applies the result. Membership is left as it is when a source
+    /// fails or nothing is found, so a bad file or DNS outage does not empty the cluster.
+    pub async fn refresh(&self) -> Result<(), String> {
+        let mut endpoints = vec![];
+        if let Some(file) = &self.config.file {
+            let content = tokio::fs::read(file)
+                .await
+                .map_err(|e| format!("reading {}: {}", file.display(), e))?;
+            let parsed = serde_json::from_slice::<EndpointFile>(&content)
+                .map_err(|e| format!("parsing {}: {}", file.display(), e))?;
+            endpoints.extend(match parsed {
+                EndpointFile::List(list) => list,
+                EndpointFile::Object { endpoints } => endpoints,
+            });
+        }
+        if let Some(name) = &self.config.dns {
+            let addrs = tokio::net::lookup_host(name.as_str())
+                .await
+                .map_err(|e| format!("resolving {}: {}", name, e))?;
+            endpoints.extend(addrs.map(|addr| Endpoint::new(&addr.ip().to_string(), addr.port(), self.config.tls)));
+        }
+        if let Some(name) = &self.config.srv {
+            endpoints.extend(self.lookup_srv(name).await?);
+        }
+
+        if endpoints.is_empty() {
+            return Err("no endpoints found, keeping the current members".to_string());
+        }
+        self.apply(endpoints);
+        Ok(())
+    }
+
+    async fn lookup_srv(&self, name: &str) -> Result<Vec<Endpoint>, String> {
+        let resolver = self
+            .resolver
+            .get_or_init(|| async {
+                TokioResolver::builder_tokio()
+                    .and_then(|builder| builder.build())
+                    .map_err(|e| warn!("Could not set up the system DNS resolver: {}", e))
+                    .ok()
+            })
+            .await
+            .as_ref()
+            .ok_or("no DNS resolver for SRV lookups")?;
+        let lookup = resolver.srv_lookup(name).await.map_err(|e| format!("looking up SRV {}: {}", name, e))?;
+        let records: Vec<_> = lookup
+            .answers()
+            .iter()
+            .filter_map(|record| match &record.data {
+                RData::SRV(srv) => Some(srv),
+                _ => None,
+            })
+            .collect();
+        let priority = records.iter().map(|srv| srv.priority).min();
+        Ok(records
+            .iter()
+            .filter(|srv| Some(srv.priority) == priority)
+            .map(|srv| {
+                let target = srv.target.to_utf8();
+                Endpoint::new(target.trim_end_matches('.'), srv.port, self.config.tls)
+            })
+            .collect())
+    }
+
+    fn apply(&self, mut endpoints: Vec<Endpoint>) {
+        let mut seen = std::collections::HashSet::new();
+        endpoints.retain(|endpoint| seen.insert(endpoint.clone()));
+
+        let mut members = self.members.write().unwrap();
+        if **members == endpoints {
+            return;
+        }
+        let in_flight = self.in_flight.lock().unwrap();
+        let mut draining = self.draining.lock().unwrap();
+        for endpoint in endpoints.iter().filter(|e| !members.contains(e)) {
+            info!("Upstream endpoint {} added", endpoint);
+            draining.remove(endpoint);
+        }
+        for endpoint in members.iter().filter(|e| !endpoints.contains(e)) {
+            if in_flight.get(endpoint).copied().unwrap_or(0) > 0 {
+                info!("Upstream endpoint {} removed, draining its requests", endpoint);
+                draining.insert(endpoint.clone(), Instant::now());
+            } else {
+                info!("Upstream endpoint {} removed", endpoint);
+            }
+        }
+        *members = Arc::new(endpoints);
+    }
+
+    fn finish(&self, endpoint: &Endpoint) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let Some(count) = in_flight.get_mut(endpoint) else { return };
+        *count -= 1;
+        if *count == 0 {
+            in_flight.remove(endpoint);
+            if self.draining.lock().unwrap().remove(endpoint).is_some() {
+                info!("Upstream endpoint {} drained", endpoint);
+            }
+        }
+    }
+
+    fn expire_draining(&self) {
+        let timeout = Duration::from_millis(self.config.drain_timeout_ms);
+        self.draining.lock().unwrap().retain(|endpoint, removed| {
+            let keep = removed.elapsed() < timeout;
+            if !keep {
+                warn!("Upstream endpoint {} still busy after the drain timeout of {:?}", endpoint, timeout);
+            }
+            keep
+        });
+    }
+
+    fn spawn_refresh(self: &Arc<Self>) {
+        let discovery: Weak<Self> = Arc::downgrade(self);
+        let interval = Duration::from_millis(self.config.refresh_ms.max(100));
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                /* stops once the handler owning the discovery is dropped */
+                let Some(discovery) = discovery.upgrade() else { break };
+                if let Err(e) = discovery.refresh().await {
+                    debug!("Service discovery refresh failed: {}", e);
+                }
+                discovery.expire_draining();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_membership_updates_and_drains() {
+        let file = std::env::temp_dir().join(format!("hyper-line-discovery-{}.json", std::process::id()));
+        std::fs::write(&file, r#"["http://10.0.0.1:80", "http://10.0.0.2:80"]"#).unwrap();
+        let discovery = ServiceDiscovery::new(DiscoveryConfig {
+            file: Some(file.clone()),
+            ..DiscoveryConfig::default()
+        });
+        assert_eq!(discovery.endpoints().await.len(), 2);
+
+        let removed: Endpoint = "http://10.0.0.2:80".parse().unwrap();
+        let request = discovery.track(&removed);
+        std::fs::write(&file, r#"{"endpoints": ["http://10.0.0.1:80", "http://10.0.0.3:80"]}"#).unwrap();
+        discovery.refresh().await.unwrap();
+        assert!(!discovery.members().contains(&removed));
+        assert_eq!(discovery.draining(), vec![removed]);
+
+        drop(request);
+        assert!(discovery.draining().is_empty());
+
+        std::fs::write(&file, "not json").unwrap();
+        assert!(discovery.refresh().await.is_err());
+        assert_eq!(discovery.members().len(), 2);
+        std::fs::remove_file(&file).unwrap();
+    }
+}
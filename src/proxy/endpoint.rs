@@ -0,0 +1,131 @@
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use hyper::Uri;
+use serde::Deserialize;
+
+/// A single upstream address, written in configuration as `http://host:port`, `https://host:port`
+/// or `unix:/path/to.sock`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Endpoint {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    /// Unix domain socket the upstream listens on, `host` is then only used for the `Host` header.
+    pub socket: Option<PathBuf>,
+}
+
+impl Endpoint {
+    pub fn new(host: &str, port: u16, tls: bool) -> Self {
+        Self {
+            tls,
+            host: host.to_string(),
+            port,
+            socket: None,
+        }
+    }
+
+    pub fn unix(path: impl Into<PathBuf>) -> Self {
+        Self {
+            tls: false,
+            host: "localhost".to_string(),
+            port: 80,
+            socket: Some(path.into()),
+        }
+    }
+
+    pub fn scheme(&self) -> &'static str {
+        match (&self.socket, self.tls) {
+            (Some(_), _) => "unix",
+            (None, true) => "https",
+            (None, false) => "http",
+        }
+    }
+
+    pub fn authority(&self) -> String {
+        match self.socket {
+            Some(_) => self.host.clone(),
+            None => format!("{}:{}", self.host, self.port),
+        }
+    }
+
+    /// Base url requests are forwarded to, the request path and query are appended to it.
+    /// Socket paths are hex encoded into the host so that each socket gets its own pool.
+    pub fn base_url(&self) -> String {
+        match &self.socket {
+            Some(path) => format!("unix://{}", encode_socket_path(path)),
+            None => format!("{}://{}:{}", self.scheme(), self.host, self.port),
+        }
+    }
+}
+
+/// Socket path of a `unix://` url made by `Endpoint::base_url`.
+pub fn decode_socket_path(uri: &Uri) -> Option<PathBuf> {
+    let hex = uri.host()?.as_bytes();
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let bytes = hex
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+fn encode_socket_path(path: &Path) -> String {
+    path.to_string_lossy().bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.socket {
+            Some(path) => write!(f, "unix:{}", path.display()),
+            None => f.write_str(&self.base_url()),
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(format!("endpoint '{}' has no socket path", s));
+            }
+            return Ok(Self::unix(path));
+        }
+        let uri: Uri = s.parse().map_err(|e| format!("invalid endpoint '{}': {}", s, e))?;
+        let tls = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => return Err(format!("endpoint '{}' must use http, https or unix", s)),
+        };
+        let host = uri.host().ok_or(format!("endpoint '{}' has no host", s))?;
+        let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+        Ok(Self::new(host, port, tls))
+    }
+}
+
+impl TryFrom<String> for Endpoint {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unix_endpoint_round_trips_through_url() {
+        let endpoint: Endpoint = "unix:/run/app/api.sock".parse().unwrap();
+        assert_eq!(endpoint.to_string(), "unix:/run/app/api.sock");
+        let uri: Uri = format!("{}/items?id=1", endpoint.base_url()).parse().unwrap();
+        assert_eq!(uri.scheme_str(), Some("unix"));
+        assert_eq!(decode_socket_path(&uri).unwrap(), PathBuf::from("/run/app/api.sock"));
+    }
+}
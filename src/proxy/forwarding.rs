@@ -0,0 +1,265 @@
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use hyper::header::{HeaderName, HeaderValue, FORWARDED, HOST, VIA};
+use hyper::{HeaderMap, Request, Version};
+use ipnet::IpNet;
+use log::debug;
+use serde::Deserialize;
+use crate::handler::reverse_proxy_handler::ProxyError;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ForwardingMode {
+    /// Keeps the values set by a trusted downstream proxy and adds this hop to them.
+    #[serde(alias = "append")]
+    #[default]
+    Append,
+
+    /// Drops inbound values and describes only this hop.
+    #[serde(alias = "replace")]
+    Replace,
+
+    /// Removes all forwarding headers.
+    #[serde(alias = "strip")]
+    Strip,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ForwardingHeaders {
+    /// `X-Forwarded-For`, `X-Forwarded-Proto`, `X-Forwarded-Host` and `X-Forwarded-Port`.
+    #[serde(alias = "legacy")]
+    #[default]
+    Legacy,
+
+    /// RFC 7239 `Forwarded`.
+    #[serde(alias = "forwarded")]
+    Forwarded,
+
+    #[serde(alias = "both")]
+    Both,
+}
+
+/// How a `ReverseProxyHandler` tells the upstream about the original client.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ForwardingPolicy {
+    pub mode: ForwardingMode,
+    pub headers: ForwardingHeaders,
+    /// Peers whose forwarding headers are kept in `Append` mode, anyone else's are dropped.
+    pub trusted_proxies: Vec<IpNet>,
+    /// Name this proxy adds to the `Via` header, `None` leaves `Via` untouched.
+    pub via: Option<String>,
+}
+
+impl Default for ForwardingPolicy {
+    fn default() -> Self {
+        Self {
+            mode: ForwardingMode::Append,
+            headers: ForwardingHeaders::Legacy,
+            trusted_proxies: vec![],
+            via: Some("hyper-line".to_string()),
+        }
+    }
+}
+
+fn x_forwarded_for_header() -> &'static HeaderName {
+    static X_FORWARDED_FOR: OnceLock<HeaderName> = OnceLock::new();
+    X_FORWARDED_FOR.get_or_init(|| HeaderName::from_static("x-forwarded-for"))
+}
+
+fn x_forwarded_proto_header() -> &'static HeaderName {
+    static X_FORWARDED_PROTO: OnceLock<HeaderName> = OnceLock::new();
+    X_FORWARDED_PROTO.get_or_init(|| HeaderName::from_static("x-forwarded-proto"))
+}
+
+fn x_forwarded_host_header() -> &'static HeaderName {
+    static X_FORWARDED_HOST: OnceLock<HeaderName> = OnceLock::new();
+    X_FORWARDED_HOST.get_or_init(|| HeaderName::from_static("x-forwarded-host"))
+}
+
+fn x_forwarded_port_header() -> &'static HeaderName {
+    static X_FORWARDED_PORT: OnceLock<HeaderName> = OnceLock::new();
+    X_FORWARDED_PORT.get_or_init(|| HeaderName::from_static("x-forwarded-port"))
+}
+
+/// What this hop knows about the request it received.
+#[derive(Debug, Clone)]
+pub struct ForwardedInfo {
+    pub client_ip: IpAddr,
+    pub proto: &'static str,
+    pub host: Option<String>,
+    pub port: u16,
+}
+
+impl ForwardedInfo {
+    pub fn from_request<B>(request: &Request<B>, client_ip: IpAddr, tls: bool, server_port: u16) -> Self {
+        let host = request
+            .headers()
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .map(str::to_string)
+            .or_else(|| request.uri().authority().map(|authority| authority.to_string()));
+        let port = host
+            .as_deref()
+            .and_then(|host| host.rsplit_once(':'))
+            .and_then(|(_, port)| port.parse().ok())
+            .unwrap_or(server_port);
+        Self {
+            client_ip,
+            proto: if tls { "https" } else { "http" },
+            host,
+            port,
+        }
+    }
+}
+
+impl ForwardingPolicy {
+    fn is_trusted(&self, peer: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&peer))
+    }
+
+    /// Rewrites the forwarding headers of a request that is about to be sent upstream.
+    pub fn apply(&self, headers: &mut HeaderMap, info: &ForwardedInfo, version: Version) -> Result<(), ProxyError> {
+        let keep_inbound = self.mode == ForwardingMode::Append && self.is_trusted(info.client_ip);
+        if !keep_inbound {
+            debug!("Dropping inbound forwarding headers from {}", info.client_ip);
+            for header in [
+                x_forwarded_for_header(),
+                x_forwarded_proto_header(),
+                x_forwarded_host_header(),
+                x_forwarded_port_header(),
+                &FORWARDED,
+            ] {
+                headers.remove(header);
+            }
+        }
+
+        if self.mode != ForwardingMode::Strip {
+            if matches!(self.headers, ForwardingHeaders::Legacy | ForwardingHeaders::Both) {
+                append_value(headers, x_forwarded_for_header(), &info.client_ip.to_string())?;
+                /* the original values describe the client, a trusted proxy's are kept */
+                if !headers.contains_key(x_forwarded_proto_header()) {
+                    headers.insert(x_forwarded_proto_header(), HeaderValue::from_static(info.proto));
+                }
+                if let Some(host) = &info.host {
+                    if !headers.contains_key(x_forwarded_host_header()) {
+                        headers.insert(x_forwarded_host_header(), host.parse()?);
+                    }
+                }
+                if !headers.contains_key(x_forwarded_port_header()) {
+                    headers.insert(x_forwarded_port_header(), info.port.into());
+                }
+            }
+            if matches!(self.headers, ForwardingHeaders::Forwarded | ForwardingHeaders::Both) {
+                append_value(headers, &FORWARDED, &forwarded_element(info))?;
+            }
+        }
+
+        self.apply_via(headers, version)
+    }
+
+    /// Adds this proxy to the `Via` header of a request or response.
+    pub fn apply_via(&self, headers: &mut HeaderMap, version: Version) -> Result<(), ProxyError> {
+        if let Some(pseudonym) = &self.via {
+            append_value(headers, &VIA, &format!("{} {}", protocol_version(version), pseudonym))?;
+        }
+        Ok(())
+    }
+}
+
+fn protocol_version(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    }
+}
+
+/* joins all existing values of the header with the new one, as a single comma separated list */
+fn append_value(headers: &mut HeaderMap, name: &HeaderName, value: &str) -> Result<(), ProxyError> {
+    let mut combined = String::new();
+    for existing in headers.get_all(name) {
+        combined.push_str(existing.to_str()?);
+        combined.push_str(", ");
+    }
+    combined.push_str(value);
+    headers.insert(name, combined.parse()?);
+    Ok(())
+}
+
+fn forwarded_element(info: &ForwardedInfo) -> String {
+    let node = match info.client_ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    };
+    let mut element = format!("for={};proto={}", node, info.proto);
+    if let Some(host) = &info.host {
+        element.push_str(";host=");
+        element.push_str(&quote_if_needed(host));
+    }
+    element
+}
+
+fn quote_if_needed(value: &str) -> String {
+    let is_token = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn info(client_ip: &str) -> ForwardedInfo {
+        ForwardedInfo {
+            client_ip: client_ip.parse().unwrap(),
+            proto: "https",
+            host: Some("example.com:8443".to_string()),
+            port: 8443,
+        }
+    }
+
+    #[test]
+    fn test_untrusted_values_are_replaced() {
+        let mut headers = HeaderMap::new();
+        headers.insert(x_forwarded_for_header(), HeaderValue::from_static("6.6.6.6"));
+        ForwardingPolicy::default().apply(&mut headers, &info("10.0.0.1"), Version::HTTP_11).unwrap();
+        assert_eq!(headers.get(x_forwarded_for_header()).unwrap(), "10.0.0.1");
+        assert_eq!(headers.get(x_forwarded_proto_header()).unwrap(), "https");
+        assert_eq!(headers.get(x_forwarded_port_header()).unwrap(), "8443");
+        assert_eq!(headers.get(VIA).unwrap(), "1.1 hyper-line");
+    }
+
+    #[test]
+    fn test_trusted_values_are_appended() {
+        let policy = ForwardingPolicy {
+            headers: ForwardingHeaders::Both,
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+            ..ForwardingPolicy::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(x_forwarded_for_header(), HeaderValue::from_static("192.168.1.1"));
+        headers.insert(FORWARDED, HeaderValue::from_static("for=192.168.1.1"));
+        policy.apply(&mut headers, &info("10.0.0.1"), Version::HTTP_2).unwrap();
+        assert_eq!(headers.get(x_forwarded_for_header()).unwrap(), "192.168.1.1, 10.0.0.1");
+        assert_eq!(
+            headers.get(FORWARDED).unwrap(),
+            "for=192.168.1.1, for=10.0.0.1;proto=https;host=\"example.com:8443\""
+        );
+    }
+
+    #[test]
+    fn test_strip_removes_forwarding_headers() {
+        let policy = ForwardingPolicy { mode: ForwardingMode::Strip, via: None, ..ForwardingPolicy::default() };
+        let mut headers = HeaderMap::new();
+        headers.insert(x_forwarded_for_header(), HeaderValue::from_static("192.168.1.1"));
+        policy.apply(&mut headers, &info("10.0.0.1"), Version::HTTP_11).unwrap();
+        assert!(headers.is_empty());
+    }
+}
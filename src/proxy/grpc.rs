@@ -0,0 +1,119 @@
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{HeaderMap, Response, StatusCode};
+use std::sync::OnceLock;
+use crate::body::empty;
+use crate::handler::reverse_proxy_handler::ProxyError;
+use crate::HttpResponse;
+
+/// gRPC status codes used when the proxy itself has to fail a call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GrpcStatus(pub u16);
+
+impl GrpcStatus {
+    pub const UNKNOWN: GrpcStatus = GrpcStatus(2);
+    pub const DEADLINE_EXCEEDED: GrpcStatus = GrpcStatus(4);
+    pub const PERMISSION_DENIED: GrpcStatus = GrpcStatus(7);
+    pub const UNIMPLEMENTED: GrpcStatus = GrpcStatus(12);
+    pub const INTERNAL: GrpcStatus = GrpcStatus(13);
+    pub const UNAVAILABLE: GrpcStatus = GrpcStatus(14);
+    pub const UNAUTHENTICATED: GrpcStatus = GrpcStatus(16);
+
+    /// Mapping from the gRPC spec for responses that carry no `grpc-status` of their own.
+    pub fn from_http(status: StatusCode) -> Self {
+        match status.as_u16() {
+            400 => GrpcStatus::INTERNAL,
+            401 => GrpcStatus::UNAUTHENTICATED,
+            403 => GrpcStatus::PERMISSION_DENIED,
+            404 => GrpcStatus::UNIMPLEMENTED,
+            429 | 502 | 503 | 504 => GrpcStatus::UNAVAILABLE,
+            _ => GrpcStatus::UNKNOWN,
+        }
+    }
+
+    pub fn from_error(err: &ProxyError) -> Self {
+        match err {
+            ProxyError::Timeout(_) => GrpcStatus::DEADLINE_EXCEEDED,
+            ProxyError::InvalidUri(_) | ProxyError::ForwardHeaderError => GrpcStatus::INTERNAL,
+            _ => GrpcStatus::UNAVAILABLE,
+        }
+    }
+}
+
+pub fn grpc_status_header() -> &'static HeaderName {
+    static GRPC_STATUS: OnceLock<HeaderName> = OnceLock::new();
+    GRPC_STATUS.get_or_init(|| HeaderName::from_static("grpc-status"))
+}
+
+pub fn grpc_message_header() -> &'static HeaderName {
+    static GRPC_MESSAGE: OnceLock<HeaderName> = OnceLock::new();
+    GRPC_MESSAGE.get_or_init(|| HeaderName::from_static("grpc-message"))
+}
+
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/grpc"))
+}
+
+/// `grpc-status` and `grpc-message` fields, usable as trailers or in a trailers-only response.
+pub fn status_trailers(status: GrpcStatus, message: &str) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    trailers.insert(grpc_status_header(), status.0.into());
+    if let Ok(message) = HeaderValue::from_str(&percent_encode(message)) {
+        trailers.insert(grpc_message_header(), message);
+    }
+    trailers
+}
+
+/// A trailers-only gRPC response, the way a gRPC server reports a call that failed up front.
+pub fn error_response(status: GrpcStatus, message: &str) -> HttpResponse {
+    let mut res = Response::new(empty());
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+    res.headers_mut().extend(status_trailers(status, message));
+    res
+}
+
+/// Turns an upstream reply to a gRPC call that is not a gRPC response (e.g. a 502 from an
+/// intermediate proxy) into one, so clients see a gRPC status instead of a transport error.
+pub fn normalize_response(res: HttpResponse) -> HttpResponse {
+    if res.status() == StatusCode::OK || res.headers().contains_key(grpc_status_header()) {
+        return res;
+    }
+    let status = res.status();
+    error_response(GrpcStatus::from_http(status), &format!("upstream responded with {}", status))
+}
+
+/* grpc-message is percent-encoded, see the gRPC over HTTP/2 spec */
+fn percent_encode(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        if (0x20..=0x7e).contains(&byte) && byte != b'%' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_non_grpc_reply_is_normalized() {
+        let mut upstream = Response::new(empty());
+        *upstream.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        let res = normalize_response(upstream);
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(grpc_status_header()).unwrap(), "14");
+        assert_eq!(res.headers().get(grpc_message_header()).unwrap(), "upstream responded with 503 Service Unavailable");
+    }
+
+    #[test]
+    fn test_grpc_message_is_percent_encoded() {
+        let trailers = status_trailers(GrpcStatus::INTERNAL, "100% ünicode");
+        assert_eq!(trailers.get(grpc_message_header()).unwrap(), "100%25 %C3%BCnicode");
+    }
+}
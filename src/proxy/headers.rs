@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+use log::{debug, warn};
+use serde::Deserialize;
+use crate::proxy::endpoint::Endpoint;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderAction {
+    /// Appends a value, keeping those already present.
+    #[serde(alias = "add")]
+    Add,
+
+    /// Replaces all values of the header.
+    #[serde(alias = "set")]
+    Set,
+
+    #[serde(alias = "remove")]
+    Remove,
+}
+
+/// A header change. `value` may refer to exchange data with `${...}`, see `HeaderVars`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HeaderRule {
+    pub action: HeaderAction,
+    pub name: String,
+    #[serde(default)]
+    pub value: String,
+}
+
+/// Header changes applied to requests on the way to the upstream and to its responses on the
+/// way back, after hop-by-hop and forwarding headers have been handled.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HeaderRules {
+    pub request: Vec<HeaderRule>,
+    pub response: Vec<HeaderRule>,
+}
+
+/// Exchange data header rule values can refer to:
+///
+/// - `${client_ip}`, `${request_id}`, `${method}`, `${path}`, `${host}`
+/// - `${upstream}`, the endpoint the request is sent to
+/// - `${param.NAME}`, a parameter of the matched route, e.g. `/users/{NAME}`
+/// - `${env.NAME}`, an environment variable, e.g. for an internal auth token
+///
+/// Unknown names expand to nothing.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderVars {
+    pub client_ip: Option<IpAddr>,
+    pub request_id: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub host: Option<String>,
+    pub params: HashMap<String, String>,
+    pub upstream: Option<String>,
+}
+
+impl HeaderVars {
+    pub fn with_upstream(&self, endpoint: &Endpoint) -> Self {
+        Self {
+            upstream: Some(endpoint.to_string()),
+            ..self.clone()
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<String> {
+        if let Some(param) = name.strip_prefix("param.") {
+            return self.params.get(param).cloned();
+        }
+        if let Some(var) = name.strip_prefix("env.") {
+            return std::env::var(var).ok();
+        }
+        match name {
+            "client_ip" => self.client_ip.map(|ip| ip.to_string()),
+            "request_id" => self.request_id.clone(),
+            "method" => Some(self.method.clone()),
+            "path" => Some(self.path.clone()),
+            "host" => self.host.clone(),
+            "upstream" => self.upstream.clone(),
+            _ => None,
+        }
+    }
+
+    /// Replaces the `${name}` references in `template`.
+    pub fn interpolate(&self, template: &str) -> String {
+        let mut result = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find("${") {
+            let Some(len) = rest[start..].find('}') else { break };
+            result.push_str(&rest[..start]);
+            let name = &rest[start + 2..start + len];
+            match self.lookup(name) {
+                Some(value) => result.push_str(&value),
+                None => debug!("Header rule refers to unknown value '{}'", name),
+            }
+            rest = &rest[start + len + 1..];
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+impl HeaderRules {
+    pub fn apply_request(&self, headers: &mut HeaderMap, vars: &HeaderVars) {
+        apply(&self.request, headers, vars);
+    }
+
+    pub fn apply_response(&self, headers: &mut HeaderMap, vars: &HeaderVars) {
+        apply(&self.response, headers, vars);
+    }
+}
+
+fn apply(rules: &[HeaderRule], headers: &mut HeaderMap, vars: &HeaderVars) {
+    for rule in rules {
+        let Ok(name) = HeaderName::try_from(rule.name.as_str()) else {
+            warn!("Skipping header rule with invalid name '{}'", rule.name);
+            continue;
+        };
+        if rule.action == HeaderAction::Remove {
+            headers.remove(&name);
+            continue;
+        }
+        let value = match HeaderValue::from_str(&vars.interpolate(&rule.value)) {
+            Ok(value) => value,
+            Err(_) => {
+                warn!("Skipping header rule for '{}', the value is not a valid header value", rule.name);
+                continue;
+            }
+        };
+        if rule.action == HeaderAction::Set {
+            headers.insert(name, value);
+        } else {
+            headers.append(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rule(action: HeaderAction, name: &str, value: &str) -> HeaderRule {
+        HeaderRule {
+            action,
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_rules_are_applied_in_order() {
+        let rules = HeaderRules {
+            request: vec![],
+            response: vec![
+                rule(HeaderAction::Remove, "server", ""),
+                rule(HeaderAction::Set, "x-upstream", "${upstream}"),
+                rule(HeaderAction::Add, "x-trace", "${request_id}/${param.id}"),
+            ],
+        };
+        let vars = HeaderVars {
+            request_id: Some("abc".to_string()),
+            params: HashMap::from([("id".to_string(), "42".to_string())]),
+            ..HeaderVars::default()
+        }
+        .with_upstream(&"http://backend:8081".parse().unwrap());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("server", HeaderValue::from_static("nginx"));
+        headers.insert("x-upstream", HeaderValue::from_static("old"));
+        headers.insert("x-trace", HeaderValue::from_static("first"));
+        rules.apply_response(&mut headers, &vars);
+
+        assert!(headers.get("server").is_none());
+        assert_eq!(headers.get("x-upstream").unwrap(), "http://backend:8081");
+        let traces: Vec<_> = headers.get_all("x-trace").iter().collect();
+        assert_eq!(traces, ["first", "abc/42"]);
+    }
+
+    #[test]
+    fn test_unknown_and_unterminated_references() {
+        let vars = HeaderVars::default();
+        assert_eq!(vars.interpolate("a${nope}b"), "ab");
+        assert_eq!(vars.interpolate("a${nope"), "a${nope");
+    }
+}
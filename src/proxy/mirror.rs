@@ -0,0 +1,107 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use rand::Rng;
+use serde::Deserialize;
+use crate::proxy::endpoint::Endpoint;
+
+/// Sends copies of requests to a secondary upstream, e.g. to try a new backend version with
+/// production traffic. Mirrored responses are discarded and never delay the primary request.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MirrorConfig {
+    pub endpoint: Option<Endpoint>,
+    /// Share of requests mirrored, from 0 to 100.
+    pub percentage: f64,
+    /// Requests with larger bodies are not mirrored.
+    pub max_body_bytes: usize,
+    pub timeout_ms: Option<u64>,
+}
+
+impl Default for MirrorConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            percentage: 100.0,
+            max_body_bytes: 1024 * 1024,
+            timeout_ms: Some(5000),
+        }
+    }
+}
+
+impl MirrorConfig {
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_ms.map(Duration::from_millis)
+    }
+
+    /// The mirror endpoint, when this request was picked for mirroring.
+    pub fn sample(&self) -> Option<&Endpoint> {
+        let endpoint = self.endpoint.as_ref()?;
+        match self.percentage {
+            p if p <= 0.0 => None,
+            p if p >= 100.0 => Some(endpoint),
+            p => (rand::rng().random_range(0.0..100.0) < p).then_some(endpoint),
+        }
+    }
+}
+
+/// Outcome counts of the mirrored requests of a handler.
+#[derive(Debug, Default)]
+pub struct MirrorMetrics {
+    sent: AtomicU64,
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+    skipped: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MirrorStats {
+    pub sent: u64,
+    pub succeeded: u64,
+    /// Mirrored requests that failed, timed out or got a 5xx response.
+    pub failed: u64,
+    /// Requests picked for mirroring but not sent because of their body size.
+    pub skipped: u64,
+}
+
+impl MirrorMetrics {
+    pub fn record_sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_result(&self, success: bool) {
+        let counter = if success { &self.succeeded } else { &self.failed };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MirrorStats {
+        MirrorStats {
+            sent: self.sent.load(Ordering::Relaxed),
+            succeeded: self.succeeded.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_percentage_bounds() {
+        let mut config = MirrorConfig {
+            endpoint: Some("http://shadow:8081".parse().unwrap()),
+            percentage: 0.0,
+            ..MirrorConfig::default()
+        };
+        assert!((0..100).all(|_| config.sample().is_none()));
+        config.percentage = 100.0;
+        assert!((0..100).all(|_| config.sample().is_some()));
+        config.endpoint = None;
+        assert!(config.sample().is_none());
+    }
+}
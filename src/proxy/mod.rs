@@ -0,0 +1,15 @@
+pub mod circuit_breaker;
+pub mod client;
+pub mod discovery;
+pub mod endpoint;
+pub mod forwarding;
+pub mod grpc;
+pub mod headers;
+pub mod mirror;
+pub mod retry;
+pub mod rewrite;
+pub mod split;
+pub mod timeout;
+pub mod tls;
+pub mod tunnel;
+mod window;
@@ -0,0 +1,214 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use hyper::Method;
+use rand::Rng;
+use serde::Deserialize;
+use crate::handler::reverse_proxy_handler::ProxyError;
+use crate::HttpResponse;
+use crate::proxy::window::RollingWindow;
+
+/// Retry behaviour of a `ReverseProxyHandler`. The default policy makes a single attempt.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Total attempts per request, including the first one.
+    pub max_attempts: u32,
+    pub retry_on_connect_failure: bool,
+    pub retry_on_timeout: bool,
+    pub retry_on_status: Vec<u16>,
+    /// Allows retrying methods that are not idempotent (POST, PATCH, CONNECT).
+    pub retry_non_idempotent: bool,
+    pub per_try_timeout_ms: Option<u64>,
+    pub backoff: Backoff,
+    pub budget: RetryBudgetConfig,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            retry_on_connect_failure: true,
+            retry_on_timeout: true,
+            retry_on_status: vec![],
+            retry_non_idempotent: false,
+            per_try_timeout_ms: None,
+            backoff: Backoff::default(),
+            budget: RetryBudgetConfig::default(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn enabled(&self) -> bool {
+        self.max_attempts > 1
+    }
+
+    pub fn allows_method(&self, method: &Method) -> bool {
+        self.retry_non_idempotent || is_idempotent(method)
+    }
+
+    pub fn per_try_timeout(&self) -> Option<Duration> {
+        self.per_try_timeout_ms.map(Duration::from_millis)
+    }
+
+    /// Decides if the outcome of an attempt is worth another try. An open circuit is only worth
+    /// retrying when `next_endpoint_differs`, as the same endpoint would reject again.
+    pub fn should_retry(&self, result: &Result<HttpResponse, ProxyError>, next_endpoint_differs: bool) -> bool {
+        match result {
+            Ok(res) => self.retry_on_status.contains(&res.status().as_u16()),
+            Err(ProxyError::LegacyHyperError(e)) => self.retry_on_connect_failure && e.is_connect(),
+            Err(ProxyError::Timeout(_)) => self.retry_on_timeout,
+            Err(ProxyError::CircuitOpen(_)) => next_endpoint_differs,
+            Err(_) => false,
+        }
+    }
+}
+
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+/// Exponential back-off between attempts, optionally with full jitter.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Backoff {
+    pub base_ms: u64,
+    pub max_ms: u64,
+    pub jitter: bool,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            base_ms: 25,
+            max_ms: 1000,
+            jitter: true,
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay to wait after the given (1-based) failed attempt.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = self.base_ms.saturating_mul(1u64 << attempt.saturating_sub(1).min(32));
+        let capped = exp.min(self.max_ms);
+        if self.jitter && capped > 0 {
+            Duration::from_millis(rand::rng().random_range(0..=capped))
+        } else {
+            Duration::from_millis(capped)
+        }
+    }
+}
+
+/// Limits retries to a share of the requests seen in a rolling window, so that a failing
+/// upstream does not receive a multiple of its normal load.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetryBudgetConfig {
+    /// Retries allowed as a fraction of requests in the window.
+    pub ratio: f64,
+    /// Retries always allowed per second, regardless of the ratio.
+    pub min_retries_per_sec: u32,
+    pub window_secs: u64,
+}
+
+impl Default for RetryBudgetConfig {
+    fn default() -> Self {
+        Self {
+            ratio: 0.2,
+            min_retries_per_sec: 10,
+            window_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct BudgetBucket {
+    requests: u64,
+    retries: u64,
+}
+
+#[derive(Debug)]
+pub struct RetryBudget {
+    config: RetryBudgetConfig,
+    window: Mutex<RollingWindow<BudgetBucket>>,
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        Self::new(RetryBudgetConfig::default())
+    }
+}
+
+impl RetryBudget {
+    pub fn new(config: RetryBudgetConfig) -> Self {
+        let window_secs = config.window_secs.max(1);
+        Self {
+            window: Mutex::new(RollingWindow::new(Duration::from_secs(window_secs), window_secs as usize)),
+            config,
+        }
+    }
+
+    pub fn record_request(&self) {
+        self.window.lock().unwrap().current(Instant::now()).requests += 1;
+    }
+
+    /// Takes one retry out of the budget, returns false when the budget is spent.
+    pub fn try_withdraw(&self) -> bool {
+        let now = Instant::now();
+        let mut window = self.window.lock().unwrap();
+        let (requests, retries) = window
+            .live(now)
+            .fold((0u64, 0u64), |(req, ret), b| (req + b.requests, ret + b.retries));
+        let floor = self.config.min_retries_per_sec as f64 * self.config.window_secs.max(1) as f64;
+        let allowed = (requests as f64 * self.config.ratio).max(floor);
+        if (retries as f64) < allowed {
+            window.current(now).retries += 1;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let backoff = Backoff { base_ms: 100, max_ms: 300, jitter: false };
+        assert_eq!(backoff.delay(1), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2), Duration::from_millis(200));
+        assert_eq!(backoff.delay(3), Duration::from_millis(300));
+        assert_eq!(backoff.delay(40), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn test_retry_budget_exhausts() {
+        let budget = RetryBudget::new(RetryBudgetConfig { ratio: 0.5, min_retries_per_sec: 0, window_secs: 10 });
+        for _ in 0..4 {
+            budget.record_request();
+        }
+        assert!(budget.try_withdraw());
+        assert!(budget.try_withdraw());
+        assert!(!budget.try_withdraw(), "Should NOT allow more retries than half the requests.");
+    }
+
+    #[test]
+    fn test_non_idempotent_methods_are_not_retried() {
+        let policy = RetryPolicy::default();
+        assert!(policy.allows_method(&Method::GET));
+        assert!(!policy.allows_method(&Method::POST));
+    }
+
+    #[test]
+    fn test_open_circuit_is_retried_on_another_endpoint_only() {
+        let policy = RetryPolicy::default();
+        let open = Err(ProxyError::CircuitOpen("open".to_string()));
+        assert!(policy.should_retry(&open, true));
+        assert!(!policy.should_retry(&open, false), "Should fail fast against the same open circuit.");
+    }
+}
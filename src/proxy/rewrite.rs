@@ -0,0 +1,283 @@
+use hyper::header::{Entry, HeaderValue, CONTENT_LOCATION, HOST, LOCATION, REFRESH, SET_COOKIE};
+use hyper::{HeaderMap, Request, Uri};
+use log::debug;
+use serde::Deserialize;
+use crate::proxy::endpoint::Endpoint;
+use crate::proxy::forwarding::ForwardedInfo;
+
+/// `Host` header sent to the upstream.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub enum HostHeader {
+    /// Passes on the `Host` the client sent.
+    #[serde(alias = "preserve")]
+    #[default]
+    Preserve,
+
+    /// Uses the address of the upstream endpoint.
+    #[serde(alias = "upstream")]
+    Upstream,
+
+    #[serde(alias = "custom")]
+    Custom(String),
+}
+
+/// Request and response rewriting needed when the upstream is published under another host or
+/// path than its own. Response headers are passed on unchanged unless a rewrite is enabled.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RewriteConfig {
+    pub host_header: HostHeader,
+    /// Public path the upstream is mounted under. It is stripped from forwarded request paths
+    /// and put back in front of the paths the upstream hands out.
+    pub path_prefix: Option<String>,
+    /// Rewrites `Location`, `Content-Location` and `Refresh` urls that point at the upstream.
+    pub rewrite_location: bool,
+    pub rewrite_cookie_domain: bool,
+    pub rewrite_cookie_path: bool,
+}
+
+impl Default for RewriteConfig {
+    fn default() -> Self {
+        Self {
+            host_header: HostHeader::Preserve,
+            path_prefix: None,
+            rewrite_location: false,
+            rewrite_cookie_domain: false,
+            rewrite_cookie_path: false,
+        }
+    }
+}
+
+impl RewriteConfig {
+    fn prefix(&self) -> &str {
+        self.path_prefix.as_deref().unwrap_or("").trim_end_matches('/')
+    }
+
+    /// Removes the public path prefix from the request path.
+    pub fn rewrite_request_path<B>(&self, request: &mut Request<B>) {
+        let prefix = self.prefix();
+        if prefix.is_empty() {
+            return;
+        }
+        let path = request.uri().path();
+        let stripped = match path.strip_prefix(prefix) {
+            Some("") => "/",
+            Some(rest) if rest.starts_with('/') => rest,
+            _ => return,
+        };
+        let path_and_query = match request.uri().query() {
+            Some(query) => format!("{}?{}", stripped, query),
+            None => stripped.to_string(),
+        };
+        let mut parts = request.uri().clone().into_parts();
+        parts.path_and_query = path_and_query.parse().ok();
+        if let Ok(uri) = Uri::from_parts(parts) {
+            debug!("Stripped path prefix, forwarding {}", uri);
+            *request.uri_mut() = uri;
+        }
+    }
+
+    /// Sets the `Host` header of a request about to be sent to `endpoint`, before its url is
+    /// replaced by the upstream's. HTTP/2 clients send the host as the `:authority` of the url
+    /// instead, which is kept as the `Host` header. Requests to a Unix socket that have neither
+    /// get the endpoint's host, as their url holds the socket path.
+    pub fn rewrite_host<B>(&self, request: &mut Request<B>, endpoint: &Endpoint) {
+        let headers = request.headers();
+        let host = match &self.host_header {
+            HostHeader::Preserve if headers.contains_key(HOST) => return,
+            HostHeader::Preserve => match request.uri().authority() {
+                Some(authority) => authority.to_string(),
+                None if endpoint.socket.is_some() => endpoint.authority(),
+                None => return,
+            },
+            HostHeader::Upstream => endpoint.authority(),
+            HostHeader::Custom(host) => host.clone(),
+        };
+        if let Ok(value) = HeaderValue::from_str(&host) {
+            request.headers_mut().insert(HOST, value);
+        }
+    }
+
+    /// Points urls and cookies the upstream returned back at the public host and path.
+    pub fn rewrite_response(&self, headers: &mut HeaderMap, endpoint: &Endpoint, public: &ForwardedInfo) {
+        if self.rewrite_location {
+            for name in [LOCATION, CONTENT_LOCATION] {
+                if let Some(value) = headers.get(&name).and_then(|v| v.to_str().ok()) {
+                    let rewritten = self.rewrite_url(value, endpoint, public);
+                    if let Ok(value) = HeaderValue::from_str(&rewritten) {
+                        headers.insert(name, value);
+                    }
+                }
+            }
+            if let Some(value) = headers.get(REFRESH).and_then(|v| v.to_str().ok()) {
+                let rewritten = self.rewrite_refresh(value, endpoint, public);
+                if let Ok(value) = HeaderValue::from_str(&rewritten) {
+                    headers.insert(REFRESH, value);
+                }
+            }
+        }
+
+        if self.rewrite_cookie_domain || self.rewrite_cookie_path {
+            if let Entry::Occupied(mut cookies) = headers.entry(SET_COOKIE) {
+                /* cookies that are not visible ASCII are passed on as they are */
+                for value in cookies.iter_mut() {
+                    let Ok(cookie) = value.to_str() else { continue };
+                    if let Ok(rewritten) = HeaderValue::from_str(&self.rewrite_cookie(cookie, endpoint, public)) {
+                        *value = rewritten;
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_upstream_authority(&self, uri: &Uri, endpoint: &Endpoint) -> bool {
+        let Some(host) = uri.host() else { return false };
+        let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+        let upstream = host.eq_ignore_ascii_case(&endpoint.host) && port == endpoint.port;
+        let custom = matches!(&self.host_header, HostHeader::Custom(custom)
+            if uri.authority().is_some_and(|a| a.as_str().eq_ignore_ascii_case(custom)));
+        upstream || custom
+    }
+
+    fn rewrite_url(&self, url: &str, endpoint: &Endpoint, public: &ForwardedInfo) -> String {
+        if url.starts_with('/') && !url.starts_with("//") {
+            return format!("{}{}", self.prefix(), url);
+        }
+        let Ok(uri) = url.parse::<Uri>() else { return url.to_string() };
+        match (&public.host, self.is_upstream_authority(&uri, endpoint)) {
+            (Some(public_host), true) => {
+                let path_and_query = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+                format!("{}://{}{}{}", public.proto, public_host, self.prefix(), path_and_query)
+            }
+            _ => url.to_string(),
+        }
+    }
+
+    /* Refresh: "5; url=http://upstream/next" */
+    fn rewrite_refresh(&self, value: &str, endpoint: &Endpoint, public: &ForwardedInfo) -> String {
+        let Some(pos) = value.to_ascii_lowercase().find("url=") else { return value.to_string() };
+        let (head, url) = value.split_at(pos + 4);
+        let quoted = url.starts_with('\'') || url.starts_with('"');
+        let bare = url.trim_matches(|c| c == '\'' || c == '"');
+        let rewritten = self.rewrite_url(bare, endpoint, public);
+        if quoted {
+            format!("{}'{}'", head, rewritten)
+        } else {
+            format!("{}{}", head, rewritten)
+        }
+    }
+
+    fn rewrite_cookie(&self, cookie: &str, endpoint: &Endpoint, public: &ForwardedInfo) -> String {
+        let public_host = public.host.as_deref().map(|host| match host.rsplit_once(':') {
+            Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+            _ => host,
+        });
+        let mut attributes = cookie.split(';').map(str::trim).collect::<Vec<_>>();
+        let mut rewritten = Vec::with_capacity(attributes.len());
+        rewritten.push(attributes.remove(0).to_string());
+        for attribute in attributes {
+            let (name, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            if self.rewrite_cookie_domain && name.eq_ignore_ascii_case("domain") {
+                if value.trim_start_matches('.').eq_ignore_ascii_case(&endpoint.host) {
+                    if let Some(public_host) = public_host {
+                        rewritten.push(format!("{}={}", name, public_host));
+                    }
+                    continue;
+                }
+            } else if self.rewrite_cookie_path && name.eq_ignore_ascii_case("path") && value.starts_with('/') {
+                rewritten.push(format!("{}={}{}", name, self.prefix(), value));
+                continue;
+            }
+            rewritten.push(attribute.to_string());
+        }
+        rewritten.join("; ")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rewrite() -> RewriteConfig {
+        RewriteConfig {
+            path_prefix: Some("/app".to_string()),
+            rewrite_location: true,
+            rewrite_cookie_domain: true,
+            rewrite_cookie_path: true,
+            ..RewriteConfig::default()
+        }
+    }
+
+    fn public() -> ForwardedInfo {
+        ForwardedInfo {
+            client_ip: "10.0.0.1".parse().unwrap(),
+            proto: "https",
+            host: Some("example.com".to_string()),
+            port: 443,
+        }
+    }
+
+    #[test]
+    fn test_location_is_rewritten() {
+        let endpoint: Endpoint = "http://10.1.1.1:8081".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(LOCATION, HeaderValue::from_static("http://10.1.1.1:8081/login?next=1"));
+        headers.insert(CONTENT_LOCATION, HeaderValue::from_static("/items/1"));
+        headers.insert(REFRESH, HeaderValue::from_static("5; url=http://10.1.1.1:8081/done"));
+        rewrite().rewrite_response(&mut headers, &endpoint, &public());
+        assert_eq!(headers.get(LOCATION).unwrap(), "https://example.com/app/login?next=1");
+        assert_eq!(headers.get(CONTENT_LOCATION).unwrap(), "/app/items/1");
+        assert_eq!(headers.get(REFRESH).unwrap(), "5; url=https://example.com/app/done");
+    }
+
+    #[test]
+    fn test_cookie_domain_and_path_are_rewritten() {
+        let endpoint: Endpoint = "http://backend:8081".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.append(SET_COOKIE, HeaderValue::from_static("id=1; Domain=backend; Path=/; HttpOnly"));
+        headers.append(SET_COOKIE, HeaderValue::from_static("other=2; Domain=cdn.net"));
+        rewrite().rewrite_response(&mut headers, &endpoint, &public());
+        let cookies: Vec<_> = headers.get_all(SET_COOKIE).iter().collect();
+        assert_eq!(cookies[0], "id=1; Domain=example.com; Path=/app/; HttpOnly");
+        assert_eq!(cookies[1], "other=2; Domain=cdn.net");
+    }
+
+    #[test]
+    fn test_unparsable_cookie_is_kept() {
+        let endpoint: Endpoint = "http://backend:8081".parse().unwrap();
+        let opaque = HeaderValue::from_bytes(b"name=caf\xe9; Domain=backend").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.append(SET_COOKIE, opaque.clone());
+        headers.append(SET_COOKIE, HeaderValue::from_static("id=1; Domain=backend"));
+        rewrite().rewrite_response(&mut headers, &endpoint, &public());
+        let cookies: Vec<_> = headers.get_all(SET_COOKIE).iter().collect();
+        assert_eq!(cookies, [&opaque, &HeaderValue::from_static("id=1; Domain=example.com")]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(LOCATION, HeaderValue::from_static("http://backend:8081/login"));
+        RewriteConfig::default().rewrite_response(&mut headers, &endpoint, &public());
+        assert_eq!(headers.get(LOCATION).unwrap(), "http://backend:8081/login", "Should not rewrite by default.");
+    }
+
+    #[test]
+    fn test_http2_authority_is_kept_as_host() {
+        let endpoint: Endpoint = "http://10.1.1.1:8081".parse().unwrap();
+        let mut request = Request::new(());
+        *request.uri_mut() = "https://example.com/items".parse().unwrap();
+        RewriteConfig::default().rewrite_host(&mut request, &endpoint);
+        assert_eq!(request.headers().get(HOST).unwrap(), "example.com");
+
+        let mut request = Request::new(());
+        request.headers_mut().insert(HOST, HeaderValue::from_static("client.test"));
+        RewriteConfig::default().rewrite_host(&mut request, &endpoint);
+        assert_eq!(request.headers().get(HOST).unwrap(), "client.test");
+    }
+
+    #[test]
+    fn test_request_path_prefix_is_stripped() {
+        let mut request = Request::new(());
+        *request.uri_mut() = "/app/items?id=3".parse().unwrap();
+        rewrite().rewrite_request_path(&mut request);
+        assert_eq!(request.uri(), "/items?id=3");
+    }
+}
@@ -0,0 +1,254 @@
+use hyper::header::{HeaderName, HeaderValue, COOKIE};
+use hyper::HeaderMap;
+use rand::Rng;
+use serde::Deserialize;
+use crate::proxy::endpoint::Endpoint;
+
+/// A set of endpoints receiving a weighted share of the traffic, e.g. a canary cluster.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpstreamGroup {
+    pub name: String,
+    /// Relative share of requests, a group with weight 0 gets no new sessions.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    pub endpoints: Vec<Endpoint>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AffinityMode {
+    /// Every request picks a group on its own.
+    #[serde(alias = "none")]
+    #[default]
+    None,
+
+    /// Clients are issued a cookie holding a random session key.
+    #[serde(alias = "cookie")]
+    Cookie,
+
+    /// The value of a request header is the session key, e.g. a user id set by an auth proxy.
+    #[serde(alias = "header")]
+    Header,
+}
+
+/// Keeps a client on the same group and endpoint across requests.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AffinityConfig {
+    pub mode: AffinityMode,
+    pub cookie_name: String,
+    pub cookie_max_age_secs: Option<u64>,
+    pub header: Option<String>,
+}
+
+impl Default for AffinityConfig {
+    fn default() -> Self {
+        Self {
+            mode: AffinityMode::None,
+            cookie_name: "hl_affinity".to_string(),
+            cookie_max_age_secs: None,
+            header: None,
+        }
+    }
+}
+
+/// Weighted split of the traffic between upstream groups. When groups are configured they
+/// replace the destination and endpoints of the `ProxyConfig`.
+///
+/// Sessions are placed with weighted rendezvous hashing on their key, so a change of the
+/// groups or their endpoints only moves the sessions of what was added or removed.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TrafficSplit {
+    pub groups: Vec<UpstreamGroup>,
+    pub affinity: AffinityConfig,
+}
+
+/// Where a request goes: the chosen group's endpoints, the preferred one first.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub group: String,
+    pub endpoints: Vec<Endpoint>,
+    /// Affinity cookie to add to the response of a client that did not have one yet.
+    pub set_cookie: Option<HeaderValue>,
+}
+
+impl TrafficSplit {
+    pub fn enabled(&self) -> bool {
+        self.groups.iter().any(|group| group.weight > 0 && !group.endpoints.is_empty())
+    }
+
+    /// Picks the group and endpoint order for a request, `None` when no group can take it.
+    pub fn route(&self, headers: &HeaderMap) -> Option<Route> {
+        if !self.enabled() {
+            return None;
+        }
+        let mut set_cookie = None;
+        let key = match self.affinity.mode {
+            AffinityMode::None => None,
+            AffinityMode::Header => self
+                .affinity
+                .header
+                .as_ref()
+                .and_then(|name| HeaderName::try_from(name.as_str()).ok())
+                .and_then(|name| headers.get(name))
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            AffinityMode::Cookie => match find_cookie(headers, &self.affinity.cookie_name) {
+                Some(key) => Some(key),
+                None => {
+                    let key = format!("{:016x}", rand::rng().random::<u64>());
+                    set_cookie = self.affinity_cookie(&key);
+                    Some(key)
+                }
+            },
+        };
+
+        let group = match &key {
+            Some(key) => self.groups_by_rendezvous(key),
+            None => self.random_group(),
+        }?;
+        let endpoints = match &key {
+            Some(key) => {
+                let mut endpoints = group.endpoints.clone();
+                endpoints.sort_by_key(|endpoint| std::cmp::Reverse(hash(key, &endpoint.to_string())));
+                endpoints
+            }
+            None => group.endpoints.clone(),
+        };
+        Some(Route { group: group.name.clone(), endpoints, set_cookie })
+    }
+
+    fn eligible(&self) -> impl Iterator<Item = &UpstreamGroup> {
+        self.groups.iter().filter(|group| group.weight > 0 && !group.endpoints.is_empty())
+    }
+
+    /* weighted rendezvous hashing: score = -weight / ln(u), u uniform in (0, 1) from the hash */
+    fn groups_by_rendezvous(&self, key: &str) -> Option<&UpstreamGroup> {
+        self.eligible()
+            .map(|group| {
+                let u = (hash(key, &group.name) as f64 + 0.5) / (u64::MAX as f64 + 1.0);
+                (-(group.weight as f64) / u.ln(), group)
+            })
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, group)| group)
+    }
+
+    fn random_group(&self) -> Option<&UpstreamGroup> {
+        let total: u64 = self.eligible().map(|group| group.weight as u64).sum();
+        let mut pick = rand::rng().random_range(0..total);
+        self.eligible().find(|group| match pick.checked_sub(group.weight as u64) {
+            Some(rest) => {
+                pick = rest;
+                false
+            }
+            None => true,
+        })
+    }
+
+    fn affinity_cookie(&self, key: &str) -> Option<HeaderValue> {
+        let mut cookie = format!("{}={}; Path=/; HttpOnly", self.affinity.cookie_name, key);
+        if let Some(max_age) = self.affinity.cookie_max_age_secs {
+            cookie.push_str(&format!("; Max-Age={}", max_age));
+        }
+        HeaderValue::from_str(&cookie).ok()
+    }
+}
+
+fn find_cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie, value)| *cookie == name && !value.is_empty())
+        .map(|(_, value)| value.to_string())
+}
+
+/* FNV-1a with a splitmix64 finalizer, stable across processes and releases unlike std's hasher */
+fn hash(key: &str, node: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key.bytes().chain([0]).chain(node.bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn group(name: &str, weight: u32, endpoints: &[&str]) -> UpstreamGroup {
+        UpstreamGroup {
+            name: name.to_string(),
+            weight,
+            endpoints: endpoints.iter().map(|e| e.parse().unwrap()).collect(),
+        }
+    }
+
+    fn header_split(groups: Vec<UpstreamGroup>) -> TrafficSplit {
+        TrafficSplit {
+            groups,
+            affinity: AffinityConfig {
+                mode: AffinityMode::Header,
+                header: Some("x-user".to_string()),
+                ..AffinityConfig::default()
+            },
+        }
+    }
+
+    fn route_user(split: &TrafficSplit, user: usize) -> Route {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-user", HeaderValue::from_str(&format!("user-{}", user)).unwrap());
+        split.route(&headers).unwrap()
+    }
+
+    #[test]
+    fn test_weights_are_respected() {
+        let split = header_split(vec![
+            group("stable", 95, &["http://stable:80"]),
+            group("canary", 5, &["http://canary:80"]),
+        ]);
+        let canary = (0..10000).filter(|user| route_user(&split, *user).group == "canary").count();
+        assert!((350..650).contains(&canary), "{} of 10000 sessions on the canary", canary);
+    }
+
+    #[test]
+    fn test_affinity_survives_endpoint_changes() {
+        let before = header_split(vec![group("stable", 1, &["http://a:80", "http://b:80", "http://c:80"])]);
+        let after = header_split(vec![group("stable", 1, &["http://a:80", "http://b:80", "http://c:80", "http://d:80"])]);
+        for user in 0..1000 {
+            let old = &route_user(&before, user).endpoints[0];
+            let new = &route_user(&after, user).endpoints[0];
+            assert!(old == new || new.host == "d", "user {} moved from {} to {}", user, old, new);
+        }
+    }
+
+    #[test]
+    fn test_cookie_is_issued_once() {
+        let split = TrafficSplit {
+            groups: vec![group("stable", 1, &["http://a:80"]), group("canary", 1, &["http://b:80"])],
+            affinity: AffinityConfig { mode: AffinityMode::Cookie, ..AffinityConfig::default() },
+        };
+        let first = split.route(&HeaderMap::new()).unwrap();
+        let cookie = first.set_cookie.unwrap();
+        let pair = cookie.to_str().unwrap().split(';').next().unwrap().to_string();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_str(&format!("theme=dark; {}", pair)).unwrap());
+        for _ in 0..20 {
+            let next = split.route(&headers).unwrap();
+            assert!(next.set_cookie.is_none());
+            assert_eq!(next.group, first.group);
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use hyper::HeaderMap;
+use hyper_util::client::legacy::Error as LegacyError;
+use log::warn;
+use serde::Deserialize;
+use tokio::time::{Instant, Sleep};
+use crate::BoxError;
+
+/// Timeouts applied to every stage of a proxied request. `None` disables a stage's timeout.
+/// Waiting for the response and its body is unlimited by default, as long-lived streams such
+/// as gRPC server streams or server-sent events may legitimately stay quiet for a long time.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ProxyTimeouts {
+    /// Establishing the TCP (and TLS) connection to the upstream.
+    pub connect_timeout_ms: Option<u64>,
+    /// From sending the request until the upstream response headers arrive.
+    pub first_byte_timeout_ms: Option<u64>,
+    /// The whole exchange with the upstream, including retries and the response body.
+    pub request_timeout_ms: Option<u64>,
+    /// Longest pause allowed between two frames of the upstream response body.
+    pub idle_body_timeout_ms: Option<u64>,
+    /// Closes upgraded connections (e.g. WebSockets) when no data moves either way.
+    pub tunnel_idle_timeout_ms: Option<u64>,
+}
+
+impl Default for ProxyTimeouts {
+    fn default() -> Self {
+        Self {
+            connect_timeout_ms: Some(5000),
+            first_byte_timeout_ms: None,
+            request_timeout_ms: None,
+            idle_body_timeout_ms: None,
+            tunnel_idle_timeout_ms: Some(300000),
+        }
+    }
+}
+
+impl ProxyTimeouts {
+    pub fn connect(&self) -> Option<Duration> {
+        self.connect_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn first_byte(&self) -> Option<Duration> {
+        self.first_byte_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn request(&self) -> Option<Duration> {
+        self.request_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn idle_body(&self) -> Option<Duration> {
+        self.idle_body_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn tunnel_idle(&self) -> Option<Duration> {
+        self.tunnel_idle_timeout_ms.map(Duration::from_millis)
+    }
+}
+
+/// True when the client failed because the connect timeout elapsed.
+pub fn is_connect_timeout(err: &LegacyError) -> bool {
+    if !err.is_connect() {
+        return false;
+    }
+    let mut source = std::error::Error::source(err);
+    while let Some(err) = source {
+        if let Some(io_err) = err.downcast_ref::<io::Error>() {
+            if io_err.kind() == io::ErrorKind::TimedOut {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Response body that fails when the upstream stalls for longer than the idle timeout or the
+/// request deadline passes, so the server resets the stream instead of ending the message as if
+/// it were complete. Streams that can report errors in trailers, like gRPC, get
+/// `timeout_trailers` as their last frame instead.
+pub struct TimeoutBody<B> {
+    inner: B,
+    idle: Option<Duration>,
+    idle_sleep: Option<Pin<Box<Sleep>>>,
+    deadline: Option<Pin<Box<Sleep>>>,
+    timeout_trailers: Option<HeaderMap>,
+    timed_out: bool,
+}
+
+impl<B> TimeoutBody<B> {
+    pub fn new(inner: B, idle: Option<Duration>, deadline: Option<Instant>) -> Self {
+        Self {
+            inner,
+            idle,
+            idle_sleep: idle.map(|idle| Box::pin(tokio::time::sleep(idle))),
+            deadline: deadline.map(|deadline| Box::pin(tokio::time::sleep_until(deadline))),
+            timeout_trailers: None,
+            timed_out: false,
+        }
+    }
+
+    pub fn with_timeout_trailers(mut self, trailers: HeaderMap) -> Self {
+        self.timeout_trailers = Some(trailers);
+        self
+    }
+
+    fn time_out(&mut self, reason: String) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        warn!("{}", reason);
+        self.timed_out = true;
+        Poll::Ready(Some(match self.timeout_trailers.take() {
+            Some(trailers) => Ok(Frame::trailers(trailers)),
+            None => Err(io::Error::new(io::ErrorKind::TimedOut, reason).into()),
+        }))
+    }
+}
+
+impl<B> Body for TimeoutBody<B>
+where
+    B: Body<Data = Bytes, Error = BoxError> + Unpin,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.timed_out {
+            return Poll::Ready(None);
+        }
+
+        if let Some(deadline) = self.deadline.as_mut() {
+            if deadline.as_mut().poll(cx).is_ready() {
+                return self.time_out("Upstream response body exceeded the request timeout".to_string());
+            }
+        }
+
+        match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(frame) => {
+                if let (Some(idle), Some(sleep)) = (self.idle, self.idle_sleep.as_mut()) {
+                    sleep.as_mut().reset(Instant::now() + idle);
+                }
+                Poll::Ready(frame)
+            }
+            Poll::Pending => {
+                if let Some(sleep) = self.idle_sleep.as_mut() {
+                    if sleep.as_mut().poll(cx).is_ready() {
+                        let idle = self.idle.unwrap_or_default();
+                        return self.time_out(format!("Upstream response body was idle for longer than {:?}", idle));
+                    }
+                }
+                Poll::Pending
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.timed_out || self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    /* yields a single frame and then stalls forever */
+    struct StalledBody(Option<Bytes>);
+
+    impl Body for StalledBody {
+        type Data = Bytes;
+        type Error = BoxError;
+
+        fn poll_frame(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+            match self.0.take() {
+                Some(data) => Poll::Ready(Some(Ok(Frame::data(data)))),
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_body_fails() {
+        let mut body = TimeoutBody::new(StalledBody(Some(Bytes::from_static(b"partial"))), Some(Duration::from_millis(20)), None);
+        let first = body.frame().await.unwrap().unwrap();
+        assert_eq!(first.into_data().unwrap(), Bytes::from_static(b"partial"));
+        let err = body.frame().await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("idle"), "{}", err);
+        assert!(body.frame().await.is_none());
+    }
+}
@@ -0,0 +1,164 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use log::warn;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig as TlsClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use serde::Deserialize;
+use crate::cert_manager::{load_certs, load_private_key};
+
+/// TLS settings for connections to an https upstream.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UpstreamTlsConfig {
+    /// PEM bundle of CA certificates trusted for the upstream, in addition to the webpki roots
+    /// unless `webpki_roots` is off.
+    pub ca_file: Option<PathBuf>,
+    pub webpki_roots: bool,
+    /// Client certificate chain and key presented to upstreams requiring mutual TLS.
+    pub client_cert_file: Option<PathBuf>,
+    pub client_key_file: Option<PathBuf>,
+    /// Name sent in SNI and checked against the upstream certificate instead of the url host.
+    pub server_name: Option<String>,
+    /// Accepts any upstream certificate. Only meant for development.
+    pub insecure_skip_verify: bool,
+}
+
+impl Default for UpstreamTlsConfig {
+    fn default() -> Self {
+        Self {
+            ca_file: None,
+            webpki_roots: true,
+            client_cert_file: None,
+            client_key_file: None,
+            server_name: None,
+            insecure_skip_verify: false,
+        }
+    }
+}
+
+impl UpstreamTlsConfig {
+    /// Loads the configured files into a rustls client configuration.
+    pub fn client_config(&self) -> io::Result<TlsClientConfig> {
+        let builder = TlsClientConfig::builder();
+        let builder = if self.insecure_skip_verify {
+            warn!("Upstream certificate verification is disabled");
+            let verifier = NoVerifier(builder.crypto_provider().clone());
+            builder.dangerous().with_custom_certificate_verifier(Arc::new(verifier))
+        } else {
+            builder.with_root_certificates(self.root_store()?)
+        };
+
+        match (&self.client_cert_file, &self.client_key_file) {
+            (None, None) => Ok(builder.with_no_client_auth()),
+            (Some(cert_file), Some(key_file)) => {
+                let certs = load_certs(&cert_file.to_string_lossy())?;
+                let key = load_private_key(&key_file.to_string_lossy())?;
+                builder
+                    .with_client_auth_cert(certs, key)
+                    .map_err(|e| io::Error::other(format!("invalid client certificate {}: {}", cert_file.display(), e)))
+            }
+            _ => Err(io::Error::other("client_cert_file and client_key_file must be set together")),
+        }
+    }
+
+    /// Name to use for SNI and certificate checks in place of the url host, if overridden.
+    pub fn server_name(&self) -> io::Result<Option<ServerName<'static>>> {
+        let Some(name) = &self.server_name else { return Ok(None) };
+        ServerName::try_from(name.clone())
+            .map(Some)
+            .map_err(|e| io::Error::other(format!("invalid server_name '{}': {}", name, e)))
+    }
+
+    fn root_store(&self) -> io::Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        if self.webpki_roots {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+        if let Some(ca_file) = &self.ca_file {
+            let certs = load_certs(&ca_file.to_string_lossy())?;
+            let (added, ignored) = roots.add_parsable_certificates(certs);
+            if added == 0 {
+                return Err(io::Error::other(format!("no usable CA certificate in {}", ca_file.display())));
+            }
+            if ignored > 0 {
+                warn!("Ignored {} invalid CA certificates in {}", ignored, ca_file.display());
+            }
+        }
+        if roots.is_empty() {
+            return Err(io::Error::other("no trusted CA certificates, set ca_file or enable webpki_roots"));
+        }
+        Ok(roots)
+    }
+}
+
+/* skips the certificate chain and name checks, handshake signatures are still verified */
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_client_cert_needs_key() {
+        let config = UpstreamTlsConfig {
+            client_cert_file: Some("examples/tls/server.pem".into()),
+            ..UpstreamTlsConfig::default()
+        };
+        assert!(config.client_config().is_err());
+        let config = UpstreamTlsConfig {
+            client_key_file: Some("examples/tls/server.rsa".into()),
+            ..config
+        };
+        assert!(config.client_config().is_ok());
+    }
+
+    #[test]
+    fn test_custom_ca_only() {
+        let config = UpstreamTlsConfig {
+            ca_file: Some("examples/tls/server.pem".into()),
+            webpki_roots: false,
+            ..UpstreamTlsConfig::default()
+        };
+        assert!(config.client_config().is_ok());
+        assert!(UpstreamTlsConfig { ca_file: None, ..config }.client_config().is_err());
+    }
+}
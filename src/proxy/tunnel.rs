@@ -0,0 +1,163 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use log::{debug, warn};
+use tokio::io::{copy_bidirectional, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Instant;
+
+/// Totals over every upgraded connection (e.g. WebSocket) a handler has tunneled.
+#[derive(Debug, Default)]
+pub struct TunnelMetrics {
+    active: AtomicU64,
+    opened: AtomicU64,
+    bytes_to_upstream: AtomicU64,
+    bytes_to_client: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TunnelStats {
+    pub active: u64,
+    pub opened: u64,
+    pub bytes_to_upstream: u64,
+    pub bytes_to_client: u64,
+}
+
+impl TunnelMetrics {
+    pub fn snapshot(&self) -> TunnelStats {
+        TunnelStats {
+            active: self.active.load(Ordering::Relaxed),
+            opened: self.opened.load(Ordering::Relaxed),
+            bytes_to_upstream: self.bytes_to_upstream.load(Ordering::Relaxed),
+            bytes_to_client: self.bytes_to_client.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/* counts the bytes read from a stream and remembers when data last moved */
+struct CountingIo<'a, S> {
+    inner: S,
+    read: u64,
+    total: &'a AtomicU64,
+    last_activity: &'a AtomicU64,
+    started: Instant,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingIo<'_, S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        if read > 0 {
+            self.read += read;
+            self.total.fetch_add(read, Ordering::Relaxed);
+            self.last_activity.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingIo<'_, S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Copies data both ways between the client and upstream until either side closes or nothing
+/// moves for `idle_timeout`.
+pub async fn run_tunnel<D, U>(downstream: D, upstream: U, idle_timeout: Option<Duration>, metrics: &TunnelMetrics)
+where
+    D: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    metrics.opened.fetch_add(1, Ordering::Relaxed);
+    metrics.active.fetch_add(1, Ordering::Relaxed);
+
+    let started = Instant::now();
+    let last_activity = AtomicU64::new(0);
+    let mut downstream = CountingIo {
+        inner: downstream,
+        read: 0,
+        total: &metrics.bytes_to_upstream,
+        last_activity: &last_activity,
+        started,
+    };
+    let mut upstream = CountingIo {
+        inner: upstream,
+        read: 0,
+        total: &metrics.bytes_to_client,
+        last_activity: &last_activity,
+        started,
+    };
+
+    let idle_watchdog = async {
+        match idle_timeout {
+            None => std::future::pending::<()>().await,
+            Some(idle) => loop {
+                let last = started + Duration::from_millis(last_activity.load(Ordering::Relaxed));
+                if last.elapsed() >= idle {
+                    return;
+                }
+                tokio::time::sleep_until(last + idle).await;
+            },
+        }
+    };
+
+    tokio::select! {
+        result = copy_bidirectional(&mut downstream, &mut upstream) => {
+            if let Err(e) = result {
+                warn!("Bidirectional copy failed: {e}");
+            }
+        }
+        _ = idle_watchdog => {
+            warn!("Closing tunnel idle for longer than {:?}", idle_timeout.unwrap_or_default());
+        }
+    }
+
+    metrics.active.fetch_sub(1, Ordering::Relaxed);
+    debug!(
+        "Tunnel closed after {}ms, {} bytes to upstream, {} bytes to client",
+        started.elapsed().as_millis(),
+        downstream.read,
+        upstream.read
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_tunnel_counts_bytes_and_closes_when_idle() {
+        let metrics = TunnelMetrics::default();
+        let (mut client, downstream) = duplex(64);
+        let (upstream, mut server) = duplex(64);
+
+        let tunnel = run_tunnel(downstream, upstream, Some(Duration::from_millis(50)), &metrics);
+        let peers = async {
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            server.write_all(b"pong!").await.unwrap();
+            let mut buf = [0u8; 5];
+            client.read_exact(&mut buf).await.unwrap();
+        };
+        tokio::join!(tunnel, peers);
+
+        let stats = metrics.snapshot();
+        assert_eq!(stats.opened, 1);
+        assert_eq!(stats.active, 0);
+        assert_eq!(stats.bytes_to_upstream, 4);
+        assert_eq!(stats.bytes_to_client, 5);
+    }
+}
@@ -0,0 +1,55 @@
+use std::time::{Duration, Instant};
+
+/// Fixed number of time buckets covering a rolling window. Buckets older than the window are
+/// reset lazily the next time their slot is reused.
+#[derive(Debug)]
+pub(crate) struct RollingWindow<B: Default> {
+    start: Instant,
+    bucket_width: Duration,
+    buckets: Vec<(u64, B)>,
+}
+
+impl<B: Default> RollingWindow<B> {
+    pub(crate) fn new(window: Duration, bucket_count: usize) -> Self {
+        let bucket_count = bucket_count.max(1);
+        let bucket_width = (window / bucket_count as u32).max(Duration::from_millis(1));
+        Self {
+            start: Instant::now(),
+            bucket_width,
+            buckets: (0..bucket_count).map(|_| (0, B::default())).collect(),
+        }
+    }
+
+    fn tick(&self, now: Instant) -> u64 {
+        (now.saturating_duration_since(self.start).as_millis() / self.bucket_width.as_millis()) as u64
+    }
+
+    /// Returns the bucket for the current instant, clearing it if it belonged to an older cycle.
+    pub(crate) fn current(&mut self, now: Instant) -> &mut B {
+        let tick = self.tick(now);
+        let len = self.buckets.len() as u64;
+        let (bucket_tick, bucket) = &mut self.buckets[(tick % len) as usize];
+        if *bucket_tick != tick {
+            *bucket_tick = tick;
+            *bucket = B::default();
+        }
+        bucket
+    }
+
+    /// Iterates over every bucket that still falls inside the window.
+    pub(crate) fn live(&self, now: Instant) -> impl Iterator<Item = &B> {
+        let tick = self.tick(now);
+        let len = self.buckets.len() as u64;
+        self.buckets
+            .iter()
+            .filter(move |(bucket_tick, _)| tick.saturating_sub(*bucket_tick) < len && *bucket_tick <= tick)
+            .map(|(_, bucket)| bucket)
+    }
+
+    pub(crate) fn reset(&mut self) {
+        for (tick, bucket) in self.buckets.iter_mut() {
+            *tick = 0;
+            *bucket = B::default();
+        }
+    }
+}
@@ -1,21 +1,30 @@
+use std::cell::Cell;
 use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
 use std::net::SocketAddr;
 use std::str::FromStr;
-use std::sync::{Arc, LazyLock, RwLock};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
 use hyper_util::rt::TokioIo;
 use hyper_util::server::conn::auto;
-use tokio::net::TcpListener;
-use tokio_rustls::TlsAcceptor;
+use log::warn;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::LazyConfigAcceptor;
+use rustls::server::{Acceptor, ResolvesServerCert};
+use rustls::{AlertDescription, HandshakeKind, PeerIncompatible};
 use rustls::ServerConfig as TlsServerConfig;
 use rustls::ClientConfig as TlsClientConfig;
 use serde::Deserialize;
 use crate::{HttpHandler};
+use crate::tls_config::TlsConfig;
+use crate::cert_manager::{CertReloader, PeerIdentity};
 use crate::service::ExecutorService;
 use crate::service::ServiceExecutor;
 
-type ConfigError = Box<dyn std::error::Error>;
-
 #[derive(Deserialize, Debug, Clone, PartialOrd, PartialEq, Default)]
 pub enum HttpMethod {
 
@@ -69,12 +78,37 @@ impl FromStr for HttpMethod {
 #[derive(Default)]
 pub struct PathConfig
 {
+    /// Prefix of the request paths handled. Segments written as `{name}` match any value,
+    /// which handlers find in the `ROUTE_PARAMS` attachment.
     pub path: String,
     pub method: HttpMethod,
     pub request: Vec<HttpHandler>,
     pub response: Vec<HttpHandler>,
 }
 
+impl PathConfig {
+    /// Route parameters when `path` falls under this config.
+    pub fn match_path(&self, path: &str) -> Option<HashMap<String, String>> {
+        if !self.path.contains('{') {
+            return path.starts_with(&self.path).then(HashMap::new);
+        }
+        let mut params = HashMap::new();
+        let mut segments = path.split('/');
+        for pattern in self.path.split('/') {
+            let segment = segments.next()?;
+            match pattern.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+                Some(name) if !segment.is_empty() => {
+                    params.insert(name.to_string(), segment.to_string());
+                }
+                Some(_) => return None,
+                None if pattern == segment => {}
+                None => return None,
+            }
+        }
+        Some(params)
+    }
+}
+
 #[derive(Default)]
 pub struct ServerConfig {
     pub worker_threads: usize,
@@ -84,9 +118,134 @@ pub struct ServerConfig {
     pub tls_enabled: bool,
     pub tls_server_config: Option<TlsServerConfig>,
     pub tls_client_config: Option<TlsClientConfig>,
+    /// Certificates loaded from a `TlsConfig`, for reporting their expiry.
+    pub certificates: Option<Arc<CertReloader>>,
+    pub handshake_metrics: Arc<HandshakeMetrics>,
+    /// Time a client has to complete the TLS handshake, unlimited when unset.
+    pub tls_handshake_timeout: Option<Duration>,
     pub paths: Vec<PathConfig>,
 }
 
+/// Why a TLS handshake failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeFailure {
+    /// The client did not complete the handshake within the handshake timeout.
+    Timeout,
+    /// No protocol version both sides support.
+    ProtocolVersion,
+    /// No certificate for the server name the client asked for.
+    UnknownSni,
+    /// The client certificate was missing or invalid, or the client rejected ours.
+    BadCertificate,
+    Other,
+}
+
+impl HandshakeFailure {
+    /// Category of a handshake error. Certificate resolvers finding no certificate report that
+    /// through `record_unknown_sni`, rustls only returns a general error for it.
+    pub fn from_error(err: &io::Error) -> Self {
+        if err.kind() == io::ErrorKind::TimedOut {
+            return HandshakeFailure::Timeout;
+        }
+        let Some(err) = err.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) else {
+            return HandshakeFailure::Other;
+        };
+        match err {
+            /* clients speaking only TLS 1.1 or older do not send signature algorithms */
+            rustls::Error::PeerIncompatible(
+                PeerIncompatible::SignatureAlgorithmsExtensionRequired
+                | PeerIncompatible::ServerDoesNotSupportTls12Or13
+                | PeerIncompatible::ServerTlsVersionIsDisabledByOurConfig
+                | PeerIncompatible::SupportedVersionsExtensionRequired
+                | PeerIncompatible::Tls12NotOffered
+                | PeerIncompatible::Tls12NotOfferedOrEnabled,
+            )
+            | rustls::Error::AlertReceived(AlertDescription::ProtocolVersion) => HandshakeFailure::ProtocolVersion,
+            rustls::Error::AlertReceived(AlertDescription::UnrecognisedName) => HandshakeFailure::UnknownSni,
+            rustls::Error::InvalidCertificate(_)
+            | rustls::Error::NoCertificatesPresented
+            | rustls::Error::AlertReceived(
+                AlertDescription::BadCertificate
+                | AlertDescription::UnsupportedCertificate
+                | AlertDescription::CertificateRevoked
+                | AlertDescription::CertificateExpired
+                | AlertDescription::CertificateUnknown
+                | AlertDescription::UnknownCA,
+            ) => HandshakeFailure::BadCertificate,
+            _ => HandshakeFailure::Other,
+        }
+    }
+}
+
+impl fmt::Display for HandshakeFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HandshakeFailure::Timeout => "timeout",
+            HandshakeFailure::ProtocolVersion => "protocol_version",
+            HandshakeFailure::UnknownSni => "unknown_sni",
+            HandshakeFailure::BadCertificate => "bad_certificate",
+            HandshakeFailure::Other => "other",
+        })
+    }
+}
+
+/// Counters of the TLS handshakes of the server.
+#[derive(Debug, Default)]
+pub struct HandshakeMetrics {
+    full: AtomicU64,
+    resumed: AtomicU64,
+    timeout: AtomicU64,
+    protocol_version: AtomicU64,
+    unknown_sni: AtomicU64,
+    bad_certificate: AtomicU64,
+    other_failures: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeStats {
+    pub full: u64,
+    /// Handshakes resuming an earlier session from the cache or a ticket.
+    pub resumed: u64,
+    pub timeout: u64,
+    pub protocol_version: u64,
+    pub unknown_sni: u64,
+    pub bad_certificate: u64,
+    pub other_failures: u64,
+}
+
+impl HandshakeMetrics {
+    pub fn record(&self, kind: Option<HandshakeKind>) {
+        let counter = match kind {
+            Some(HandshakeKind::Resumed) => &self.resumed,
+            _ => &self.full,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_failure(&self, failure: HandshakeFailure) {
+        let counter = match failure {
+            HandshakeFailure::Timeout => &self.timeout,
+            HandshakeFailure::ProtocolVersion => &self.protocol_version,
+            HandshakeFailure::UnknownSni => &self.unknown_sni,
+            HandshakeFailure::BadCertificate => &self.bad_certificate,
+            HandshakeFailure::Other => &self.other_failures,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HandshakeStats {
+        HandshakeStats {
+            full: self.full.load(Ordering::Relaxed),
+            resumed: self.resumed.load(Ordering::Relaxed),
+            timeout: self.timeout.load(Ordering::Relaxed),
+            protocol_version: self.protocol_version.load(Ordering::Relaxed),
+            unknown_sni: self.unknown_sni.load(Ordering::Relaxed),
+            bad_certificate: self.bad_certificate.load(Ordering::Relaxed),
+            other_failures: self.other_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
 pub struct ServerBuilder {
     worker_threads: usize,
     worker_thread_name: String,
@@ -95,6 +254,9 @@ pub struct ServerBuilder {
     tls_enabled: bool,
     tls_server_config: Option<TlsServerConfig>,
     tls_client_config: Option<TlsClientConfig>,
+    certificates: Option<Arc<CertReloader>>,
+    handshake_metrics: Arc<HandshakeMetrics>,
+    tls_handshake_timeout: Option<Duration>,
     paths: Vec<PathConfig>,
 }
 
@@ -109,6 +271,9 @@ impl ServerBuilder {
             tls_enabled: false,
             tls_server_config: None,
             tls_client_config: None,
+            certificates: None,
+            handshake_metrics: Arc::new(HandshakeMetrics::default()),
+            tls_handshake_timeout: Some(Duration::from_secs(10)),
             paths: Vec::new(),
         }
     }
@@ -134,30 +299,75 @@ impl ServerBuilder {
         self
     }
 
+    /// Terminates TLS with the certificate `resolver` picks for each connection, e.g. a
+    /// `cert_manager::SniResolver` serving several domains on one port.
+    pub fn tls_cert_resolver(&mut self, resolver: Arc<dyn ResolvesServerCert>) -> &mut Self {
+        let mut tls_config = TlsServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver);
+        tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        self.tls_server_config(tls_config)
+    }
+
+    /// Sets up TLS from a `TlsConfig` section, loading its certificates.
+    pub fn tls(&mut self, config: &TlsConfig) -> io::Result<&mut Self> {
+        let certificates = config.certificates()?;
+        config.monitor(&certificates);
+        let server_config = config.server_config_with(certificates.clone())?;
+        let client_config = config.client_config()?;
+        self.certificates = Some(certificates);
+        self.tls_handshake_timeout = Some(Duration::from_millis(config.handshake_timeout_ms));
+        Ok(self.tls_server_config(server_config).tls_client_config(client_config))
+    }
+
+    /// TLS settings of connections to https upstreams, used by reverse proxy handlers without a
+    /// `tls` section of their own.
     pub fn tls_client_config(&mut self, value: TlsClientConfig) -> &mut Self {
         self.tls_enabled = true;
         self.tls_client_config = Some(value);
         self
     }
 
+    /// Closes connections not completing the TLS handshake within `value`, `None` waits forever.
+    pub fn tls_handshake_timeout(&mut self, value: Option<Duration>) -> &mut Self {
+        self.tls_handshake_timeout = value;
+        self
+    }
+
+    /// Counters of the handshakes of the server this builder configures.
+    pub fn handshake_metrics(&self) -> Arc<HandshakeMetrics> {
+        self.handshake_metrics.clone()
+    }
+
     pub fn add_path(&mut self, value: PathConfig) -> &mut Self {
         self.paths.push(value);
         self
     }
 
     pub fn build(self) -> ServerConfig {
-        let mut config = ServerConfig::default();
-        config.port = self.port;
-        config.worker_thread_name = self.worker_thread_name;
-        config.worker_threads = self.worker_threads;
-        config.tls_enabled = self.tls_enabled;
-        config.tls_server_config = self.tls_server_config;
-        config.paths = self.paths;
-        config
+        ServerConfig {
+            port: self.port,
+            worker_thread_name: self.worker_thread_name,
+            worker_threads: self.worker_threads,
+            tls_enabled: self.tls_enabled,
+            tls_server_config: self.tls_server_config,
+            tls_client_config: self.tls_client_config,
+            certificates: self.certificates,
+            handshake_metrics: self.handshake_metrics,
+            tls_handshake_timeout: self.tls_handshake_timeout,
+            paths: self.paths,
+            ..ServerConfig::default()
+        }
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-pub fn run_server(config: ServerConfig) -> Result<(), ()> {
+pub fn run_server(config: ServerConfig) -> io::Result<()> {
     let server_thread_name = config.worker_thread_name.clone();
     let rt = tokio::runtime::Builder::new_multi_thread()
         .worker_threads(config.worker_threads)
@@ -168,35 +378,51 @@ pub fn run_server(config: ServerConfig) -> Result<(), ()> {
         })
         .enable_io()
         .enable_time()
-        .build()
-        .unwrap();
+        .build()?;
 
     rt.block_on(async {
         let port = config.port;
         let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), port);
-        let incoming = match TcpListener::bind(&addr).await {
-            Ok(incoming) => incoming,
-            Err(_) => todo!()
-        };
+        let incoming = TcpListener::bind(&addr).await?;
 
         /* handle https server connections */
         if config.tls_enabled {
             println!("Starting to serve on https://{}", addr);
             if let Some(tls_config) = &config.tls_server_config {
                 let tls_config = Arc::new(tls_config.clone());
-                let tls_acceptor = TlsAcceptor::from(tls_config);
+                let handshake_metrics = config.handshake_metrics.clone();
+                let handshake_timeout = config.tls_handshake_timeout;
                 let exec_svc = ExecutorService::new(Arc::new(config));
                 loop {
                     let (tcp_stream, remote_addr) = match incoming.accept().await {
                         Ok(stream) => stream,
                         Err(_) => todo!()
                     };
-                    let tls_acceptor = tls_acceptor.clone();
+                    let tls_config = tls_config.clone();
+                    let handshake_metrics = handshake_metrics.clone();
                     let mut exec_svc_clone = exec_svc.clone();
                     exec_svc_clone.set_src(remote_addr);
                     tokio::spawn(async move {
-                        match tls_acceptor.accept(tcp_stream).await {
+                        let mut server_name = None;
+                        let handshake = accept_tls(tls_config, tcp_stream, &mut server_name);
+                        let (result, unknown_sni) = watch_unknown_sni(async {
+                            match handshake_timeout {
+                                Some(limit) => tokio::time::timeout(limit, handshake)
+                                    .await
+                                    .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))),
+                                None => handshake.await,
+                            }
+                        }).await;
+                        match result {
                             Ok(tls_stream) => {
+                                handshake_metrics.record(tls_stream.get_ref().1.handshake_kind());
+                                let peer_cert = tls_stream.get_ref().1.peer_certificates().and_then(|certs| certs.first());
+                                if let Some(cert) = peer_cert {
+                                    match PeerIdentity::from_certificate(cert) {
+                                        Ok(identity) => exec_svc_clone.set_peer_identity(Arc::new(identity)),
+                                        Err(e) => warn!("Failed to read client certificate of {}: {}", remote_addr, e),
+                                    }
+                                }
                                 let io = TokioIo::new(tls_stream);
 
                                 if let Err(err) = auto::Builder::new(ServiceExecutor)
@@ -206,7 +432,18 @@ pub fn run_server(config: ServerConfig) -> Result<(), ()> {
                                 }
                             },
                             Err(err) => {
-                                eprintln!("failed to perform tls handshake: {err:#?}");
+                                let failure = match unknown_sni {
+                                    true => HandshakeFailure::UnknownSni,
+                                    false => HandshakeFailure::from_error(&err),
+                                };
+                                handshake_metrics.record_failure(failure);
+                                warn!(
+                                    "TLS handshake failed: peer={} sni={} reason={} error={}",
+                                    remote_addr,
+                                    server_name.as_deref().unwrap_or("-"),
+                                    failure,
+                                    err
+                                );
                             }
                         };
                     });
@@ -238,4 +475,93 @@ pub fn run_server(config: ServerConfig) -> Result<(), ()> {
             }
         }
     })
-}
\ No newline at end of file
+}
+
+tokio::task_local! {
+    /* records whether the certificate resolver found no certificate for the handshake in progress */
+    pub(crate) static UNKNOWN_SNI: Cell<bool>;
+}
+
+/// Marks the TLS handshake in progress as failing for want of a certificate for the server name
+/// the client asked for. Called by certificate resolvers about to return `None`.
+pub fn record_unknown_sni() {
+    let _ = UNKNOWN_SNI.try_with(|unknown| unknown.set(true));
+}
+
+/* runs a handshake, also returning whether the resolver reported an unknown server name */
+async fn watch_unknown_sni<T>(handshake: impl Future<Output = T>) -> (T, bool) {
+    UNKNOWN_SNI
+        .scope(Cell::new(false), async {
+            let result = handshake.await;
+            (result, UNKNOWN_SNI.with(Cell::get))
+        })
+        .await
+}
+
+/* reads the ClientHello first to know the requested server name when the handshake fails */
+async fn accept_tls(
+    tls_config: Arc<TlsServerConfig>,
+    tcp_stream: TcpStream,
+    server_name: &mut Option<String>,
+) -> io::Result<TlsStream<TcpStream>> {
+    let start = LazyConfigAcceptor::new(Acceptor::default(), tcp_stream).await?;
+    *server_name = start.client_hello().server_name().map(str::to_string);
+    start.into_stream(tls_config).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_route_params() {
+        let config = PathConfig {
+            path: "/users/{id}/orders".to_string(),
+            ..PathConfig::default()
+        };
+        let params = config.match_path("/users/42/orders/7").unwrap();
+        assert_eq!(params.get("id").unwrap(), "42");
+        assert!(config.match_path("/users/42/carts").is_none());
+        assert!(config.match_path("/users//orders").is_none());
+        assert!(config.match_path("/users/42").is_none());
+    }
+
+    #[test]
+    fn test_plain_paths_match_by_prefix() {
+        let config = PathConfig {
+            path: "/test".to_string(),
+            ..PathConfig::default()
+        };
+        assert_eq!(config.match_path("/test/more"), Some(HashMap::new()));
+        assert!(config.match_path("/other").is_none());
+    }
+
+    #[test]
+    fn test_handshake_failure_categories() {
+        let tls_error = |err: rustls::Error| io::Error::new(io::ErrorKind::InvalidData, err);
+        let cases = [
+            (io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"), HandshakeFailure::Timeout),
+            (tls_error(rustls::Error::PeerIncompatible(PeerIncompatible::Tls12NotOfferedOrEnabled)), HandshakeFailure::ProtocolVersion),
+            (tls_error(rustls::Error::AlertReceived(AlertDescription::UnrecognisedName)), HandshakeFailure::UnknownSni),
+            (tls_error(rustls::Error::General("no server certificate chain resolved".to_string())), HandshakeFailure::Other),
+            (tls_error(rustls::Error::AlertReceived(AlertDescription::UnknownCA)), HandshakeFailure::BadCertificate),
+            (io::Error::from(io::ErrorKind::UnexpectedEof), HandshakeFailure::Other),
+        ];
+        let metrics = HandshakeMetrics::default();
+        for (err, expected) in cases {
+            let failure = HandshakeFailure::from_error(&err);
+            assert_eq!(failure, expected, "{}", err);
+            metrics.record_failure(failure);
+        }
+        let stats = metrics.snapshot();
+        assert_eq!((stats.timeout, stats.unknown_sni, stats.other_failures), (1, 1, 2));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_sni_is_reported_by_the_resolver() {
+        let (_, unknown) = watch_unknown_sni(async { record_unknown_sni() }).await;
+        assert!(unknown);
+        let (_, unknown) = watch_unknown_sni(async {}).await;
+        assert!(!unknown, "Should only report handshakes the resolver found no certificate for.");
+    }
+}
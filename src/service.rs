@@ -3,18 +3,36 @@ use std::future::Future;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::str::FromStr;
-use std::sync::Arc;
-use http_body_util::combinators::UnsyncBoxBody;
-use http_body_util::Empty;
-use hyper::body::{Bytes, Incoming};
-use hyper::{Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use hyper::body::Incoming;
+use hyper::header::HeaderName;
+use hyper::{HeaderMap, Request, Response, StatusCode};
 use http_body_util::BodyExt;
 use hyper::service::Service;
+use rand::Rng;
 use rustls::ServerConfig as TlsServerConfig;
 use crate::server::{HttpMethod, ServerConfig};
 use crate::exchange::{Exchange, AttachmentKey};
 use crate::handler::Handler;
-use crate::{HttpRequest, HttpResponse};
+use crate::{BoxError, HttpRequest, HttpResponse};
+use crate::body::empty;
+use crate::cert_manager::PeerIdentity;
+
+fn x_request_id_header() -> &'static HeaderName {
+    static X_REQUEST_ID: OnceLock<HeaderName> = OnceLock::new();
+    X_REQUEST_ID.get_or_init(|| HeaderName::from_static("x-request-id"))
+}
+
+/* the X-Request-Id the client sent, or a new random one */
+fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(x_request_id_header())
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:016x}", rand::rng().random::<u64>()))
+}
 
 #[derive(Clone)]
 pub struct ServiceExecutor;
@@ -33,6 +51,7 @@ where
 pub struct ExecutorService {
     config: Arc<ServerConfig>,
     src: Option<SocketAddr>,
+    peer_identity: Option<Arc<PeerIdentity>>,
 }
 
 impl ExecutorService {
@@ -42,6 +61,7 @@ impl ExecutorService {
         Self {
             config,
             src: None,
+            peer_identity: None,
         }
     }
 
@@ -52,6 +72,14 @@ impl ExecutorService {
         self.src = Some(src);
     }
 
+    /// Identity of the client certificate verified during the TLS handshake.
+    pub fn set_peer_identity(
+        &mut self,
+        identity: Arc<PeerIdentity>
+    ) {
+        self.peer_identity = Some(identity);
+    }
+
     pub(self) async fn execute_handler_chain(
         &self, exchange:
         &mut Exchange<HttpRequest, HttpResponse>,
@@ -71,7 +99,7 @@ impl ExecutorService {
         status_code: StatusCode
     ) -> HttpResponse
     {
-        let mut res = Response::new(UnsyncBoxBody::new(Empty::<Bytes>::new()));
+        let mut res = Response::new(empty());
         *res.status_mut() = status_code;
         res
     }
@@ -105,6 +133,9 @@ impl Service<Request<Incoming>> for ExecutorService
             let mut exchange = Exchange::new();
 
             exchange.add_attachment::<SocketAddr>(AttachmentKey::CLIENT_SRC, Box::new(src));
+            if let Some(identity) = &exec_svc_context.peer_identity {
+                exchange.add_attachment::<Arc<PeerIdentity>>(AttachmentKey::PEER_IDENTITY, Box::new(identity.clone()));
+            }
             exchange.add_attachment::<Arc<ServerConfig>>(AttachmentKey::APP_CONTEXT, Box::new(exec_svc_context.config.clone()));
 
             for path in &exec_svc_context.config.paths {
@@ -113,15 +144,16 @@ impl Service<Request<Incoming>> for ExecutorService
                     Ok(method) => method,
                     Err(_) => panic!("Could not convert method {}", &req.method().as_str())
                 };
-                if http_method == path.method && req.uri().path().starts_with(&path.path) {
+                if let Some(params) = path.match_path(req.uri().path()).filter(|_| http_method == path.method) {
+                    exchange.add_attachment::<String>(AttachmentKey::REQUEST_ID, Box::new(request_id(req.headers())));
+                    exchange.add_attachment::<HashMap<String, String>>(AttachmentKey::ROUTE_PARAMS, Box::new(params));
+
                     //exchange.buffer_request(req).await.unwrap();
                     let (parts, body) = req.into_parts();
-                    let body = match body.collect().await {
-                        Ok(x) => x,
-                        Err(_) => panic!("Failed to collect body"),
-                    }.boxed_unsync();
-                    let collected_req = Request::from_parts(parts, UnsyncBoxBody::new(body));
-                    exchange.save_input(collected_req);
+                    /* the body is passed on as it arrives, handlers buffer it if they need to. A
+                       client aborting its upload fails the body, which handlers see when reading it. */
+                    let body = body.map_err(BoxError::from).boxed_unsync();
+                    exchange.save_input(Request::from_parts(parts, body));
 
                     /* execute request chain */
                     match exec_svc_context.execute_handler_chain(&mut exchange, &path.request).await {
@@ -140,6 +172,23 @@ impl Service<Request<Incoming>> for ExecutorService
             Ok(Self::create_error_response(StatusCode::NOT_FOUND))
         };
 
-        Box::pin(async { fut.await })
+        Box::pin(fut)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn test_request_id_is_taken_or_generated() {
+        let mut headers = HeaderMap::new();
+        headers.insert(x_request_id_header(), HeaderValue::from_static("abc-123"));
+        assert_eq!(request_id(&headers), "abc-123");
+
+        let generated = request_id(&HeaderMap::new());
+        assert_eq!(generated.len(), 16);
+        assert_ne!(generated, request_id(&HeaderMap::new()), "Should generate a new id per request.");
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,398 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use rustls::crypto::CryptoProvider;
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{NoServerSessionStorage, ServerSessionMemoryCache, WebPkiClientVerifier};
+use rustls::{ClientConfig as TlsClientConfig, RootCertStore, ServerConfig as TlsServerConfig, SupportedProtocolVersion};
+use serde::Deserialize;
+use crate::tls_config::tickets::SharedTicketer;
+pub mod tickets;
+
+use crate::cert_manager::{dev_certificate, load_certs, load_crls, CertReloader, KeyManager};
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    #[serde(alias = "1.2", alias = "TLSv1.2")]
+    Tls12,
+
+    #[serde(alias = "1.3", alias = "TLSv1.3")]
+    Tls13,
+}
+
+/// A certificate chain and its private key, PEM or DER.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CertificateFiles {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+/// Certificate generated for local HTTPS, see `cert_manager::dev_certificate`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DevCertificateConfig {
+    /// Directory the certificate is written to and reused from.
+    pub dir: PathBuf,
+    pub hostnames: Vec<String>,
+    /// Signs the certificate with a local CA instead of self-signing it.
+    pub ca: bool,
+}
+
+impl Default for DevCertificateConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from(".dev-certs"),
+            hostnames: vec!["localhost".to_string(), "127.0.0.1".to_string()],
+            ca: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClientAuthMode {
+    /// Clients are not asked for a certificate.
+    #[serde(alias = "none")]
+    #[default]
+    None,
+
+    /// A certificate is requested and verified if sent, clients without one are accepted.
+    #[serde(alias = "optional")]
+    Optional,
+
+    /// Handshakes without a valid client certificate fail.
+    #[serde(alias = "required")]
+    Required,
+}
+
+/// Verification of client certificates (mutual TLS). The identity of a verified client is
+/// attached to the exchange, see `cert_manager::PeerIdentity`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ClientAuthConfig {
+    pub mode: ClientAuthMode,
+    /// PEM bundle of the CA certificates client certificates must chain to.
+    pub ca_file: Option<PathBuf>,
+    /// Revocation lists checked for every certificate of the client's chain, PEM or DER.
+    pub crl_files: Vec<PathBuf>,
+}
+
+impl ClientAuthConfig {
+    fn verifier(&self, provider: Arc<CryptoProvider>) -> io::Result<Arc<dyn ClientCertVerifier>> {
+        if self.mode == ClientAuthMode::None {
+            return Ok(WebPkiClientVerifier::no_client_auth());
+        }
+        let ca_file = self
+            .ca_file
+            .as_ref()
+            .ok_or_else(|| invalid("client_auth needs a ca_file".to_string()))?;
+        let mut roots = RootCertStore::empty();
+        let (added, _) = roots.add_parsable_certificates(load_certs(&ca_file.to_string_lossy())?);
+        if added == 0 {
+            return Err(invalid(format!("no usable CA certificate in {}", ca_file.display())));
+        }
+        let mut crls = vec![];
+        for file in &self.crl_files {
+            crls.extend(load_crls(file)?);
+        }
+        let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).with_crls(crls);
+        let builder = match self.mode {
+            ClientAuthMode::Optional => builder.allow_unauthenticated(),
+            _ => builder,
+        };
+        builder
+            .build()
+            .map_err(|e| invalid(format!("invalid client_auth configuration: {}", e)))
+    }
+}
+
+/// Monitoring of the served certificates' expiry.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ExpiryConfig {
+    /// Certificates expiring within this many days are warned about.
+    pub warn_days: u64,
+    pub check_interval_secs: u64,
+    /// Fails startup when a certificate has already expired.
+    pub refuse_expired: bool,
+}
+
+impl Default for ExpiryConfig {
+    fn default() -> Self {
+        Self {
+            warn_days: 30,
+            check_interval_secs: 3600,
+            refuse_expired: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Sessions kept in memory for resumption by session id or stateful TLS 1.3 tickets, 0
+    /// disables the cache.
+    pub cache_size: usize,
+    /// Whether sessions are resumed with stateless tickets instead of the server side cache.
+    pub tickets: bool,
+    /// Secret file the ticket keys are derived from, so that several instances can resume each
+    /// other's sessions. Without it every instance encrypts tickets with its own random keys.
+    pub ticket_key_file: Option<PathBuf>,
+    /// How often the ticket key changes. Tickets stay valid for up to two periods.
+    pub ticket_rotation_secs: u64,
+    /// Number of tickets sent to TLS 1.3 clients after the handshake, 0 disables resumption.
+    pub tls13_tickets: usize,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            cache_size: 256,
+            tickets: true,
+            ticket_key_file: None,
+            ticket_rotation_secs: 43200,
+            tls13_tickets: 2,
+        }
+    }
+}
+
+/// TLS settings of the server, turned into rustls configurations by `ServerBuilder::tls`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// Certificates served, picked by SNI. The first one is used for clients sending no or an
+    /// unknown server name.
+    pub certificates: Vec<CertificateFiles>,
+    /// Fails handshakes for server names without a certificate instead of serving the default.
+    pub strict_sni: bool,
+    /// Development certificate served when no `certificates` are configured.
+    pub dev_certificate: Option<DevCertificateConfig>,
+    /// How often the certificate files are checked for changes, unset to never reload them.
+    pub watch_interval_ms: Option<u64>,
+    pub min_version: TlsVersion,
+    pub max_version: TlsVersion,
+    /// Names of the allowed cipher suites, e.g. `TLS13_AES_256_GCM_SHA384`. Empty allows all
+    /// suites of the crypto provider.
+    pub cipher_suites: Vec<String>,
+    /// Protocols offered through ALPN, in order of preference.
+    pub alpn: Vec<String>,
+    /// Time a client has to complete the handshake after connecting.
+    pub handshake_timeout_ms: u64,
+    pub session: SessionConfig,
+    pub client_auth: ClientAuthConfig,
+    pub expiry: ExpiryConfig,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            certificates: vec![],
+            strict_sni: false,
+            dev_certificate: None,
+            watch_interval_ms: None,
+            min_version: TlsVersion::Tls12,
+            max_version: TlsVersion::Tls13,
+            cipher_suites: vec![],
+            alpn: vec!["h2".to_string(), "http/1.1".to_string()],
+            handshake_timeout_ms: 10000,
+            session: SessionConfig::default(),
+            client_auth: ClientAuthConfig::default(),
+            expiry: ExpiryConfig::default(),
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Loads the certificates and builds the configuration for accepting connections.
+    pub fn server_config(&self) -> io::Result<TlsServerConfig> {
+        let certificates = self.certificates()?;
+        self.monitor(&certificates);
+        self.server_config_with(certificates)
+    }
+
+    /// Loads the certificates to serve and checks their expiry, failing on expired ones when
+    /// `refuse_expired` is set. `monitor` starts watching them.
+    pub fn certificates(&self) -> io::Result<Arc<CertReloader>> {
+        let mut key_manager = KeyManager::default();
+        for files in &self.certificates {
+            key_manager.add_cert(files.cert_file.clone());
+            key_manager.add_key(files.key_file.clone());
+        }
+        if let (true, Some(dev)) = (self.certificates.is_empty(), &self.dev_certificate) {
+            let files = dev_certificate(&dev.dir, &dev.hostnames, dev.ca)?;
+            key_manager.add_cert(files.cert_file);
+            key_manager.add_key(files.key_file);
+        }
+        let certificates = CertReloader::new(key_manager)?;
+        certificates.set_strict_sni(self.strict_sni);
+        let expired: Vec<_> = certificates
+            .check_expiry(self.expiry_window())
+            .into_iter()
+            .filter(|cert| cert.expired())
+            .collect();
+        if self.expiry.refuse_expired && !expired.is_empty() {
+            let files: Vec<_> = expired.iter().map(|cert| cert.cert_file.display().to_string()).collect();
+            return Err(invalid(format!("expired certificates: {}", files.join(", "))));
+        }
+        Ok(certificates)
+    }
+
+    /// Starts the file watch and the expiry monitoring of `certificates` as configured. Calling
+    /// it again for the same certificates starts nothing new.
+    pub fn monitor(&self, certificates: &Arc<CertReloader>) {
+        if let Some(interval) = self.watch_interval_ms {
+            certificates.watch(Duration::from_millis(interval));
+        }
+        if self.expiry.check_interval_secs > 0 {
+            certificates.monitor_expiry(self.expiry_window(), Duration::from_secs(self.expiry.check_interval_secs));
+        }
+    }
+
+    fn expiry_window(&self) -> Duration {
+        Duration::from_secs(self.expiry.warn_days * 86400)
+    }
+
+    /// Builds the configuration for accepting connections with already loaded certificates.
+    pub fn server_config_with(&self, certificates: Arc<CertReloader>) -> io::Result<TlsServerConfig> {
+        let provider = self.provider()?;
+        let mut config = TlsServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&self.versions()?)
+            .map_err(|e| invalid(format!("invalid TLS configuration: {}", e)))?
+            .with_client_cert_verifier(self.client_auth.verifier(provider.clone())?)
+            .with_cert_resolver(certificates);
+        config.alpn_protocols = self.alpn.iter().map(|protocol| protocol.as_bytes().to_vec()).collect();
+        config.send_tls13_tickets = self.session.tls13_tickets;
+        config.session_storage = match self.session.cache_size {
+            0 => Arc::new(NoServerSessionStorage {}),
+            size => ServerSessionMemoryCache::new(size),
+        };
+        if self.session.tickets {
+            config.ticketer = match &self.session.ticket_key_file {
+                Some(file) => Arc::new(SharedTicketer::new(file.clone(), self.session.ticket_rotation_secs)?),
+                None => rustls::crypto::aws_lc_rs::Ticketer::new()
+                    .map_err(|e| io::Error::other(format!("cannot set up session tickets: {}", e)))?,
+            };
+        }
+        Ok(config)
+    }
+
+    /// Builds a configuration for outgoing connections with the same protocol versions and
+    /// cipher suites, trusting the webpki roots. ALPN is left to the connector using it.
+    pub fn client_config(&self) -> io::Result<TlsClientConfig> {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        Ok(TlsClientConfig::builder_with_provider(self.provider()?)
+            .with_protocol_versions(&self.versions()?)
+            .map_err(|e| invalid(format!("invalid TLS configuration: {}", e)))?
+            .with_root_certificates(roots)
+            .with_no_client_auth())
+    }
+
+    fn versions(&self) -> io::Result<Vec<&'static SupportedProtocolVersion>> {
+        if self.min_version > self.max_version {
+            return Err(invalid(format!(
+                "min_version {:?} is above max_version {:?}",
+                self.min_version, self.max_version
+            )));
+        }
+        Ok([(TlsVersion::Tls12, &rustls::version::TLS12), (TlsVersion::Tls13, &rustls::version::TLS13)]
+            .into_iter()
+            .filter(|(version, _)| (self.min_version..=self.max_version).contains(version))
+            .map(|(_, supported)| supported)
+            .collect())
+    }
+
+    fn provider(&self) -> io::Result<Arc<CryptoProvider>> {
+        let mut provider = CryptoProvider::get_default()
+            .map(|provider| provider.as_ref().clone())
+            .unwrap_or_else(rustls::crypto::aws_lc_rs::default_provider);
+        if self.cipher_suites.is_empty() {
+            return Ok(Arc::new(provider));
+        }
+        let mut allowed = Vec::with_capacity(self.cipher_suites.len());
+        for name in &self.cipher_suites {
+            let suite = provider
+                .cipher_suites
+                .iter()
+                .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+                .ok_or_else(|| invalid(format!("unknown cipher suite '{}'", name)))?;
+            allowed.push(*suite);
+        }
+        provider.cipher_suites = allowed;
+        Ok(Arc::new(provider))
+    }
+}
+
+fn invalid(err: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_versions_and_suites() {
+        let config: TlsConfig = serde_json::from_str(
+            r#"{
+                "certificates": [{"cert_file": "examples/tls/server.pem", "key_file": "examples/tls/server.rsa"}],
+                "min_version": "1.3",
+                "cipher_suites": ["tls13_aes_256_gcm_sha384"],
+                "alpn": ["http/1.1"]
+            }"#,
+        )
+        .unwrap();
+        let server = config.server_config().unwrap();
+        assert_eq!(server.alpn_protocols, [b"http/1.1".to_vec()]);
+        assert!(config.client_config().is_ok());
+
+        let tls12_only = TlsConfig { max_version: TlsVersion::Tls12, ..config.clone() };
+        assert!(tls12_only.server_config().is_err());
+        let unknown = TlsConfig { cipher_suites: vec!["RC4".to_string()], ..config };
+        assert!(unknown.server_config().unwrap_err().to_string().contains("unknown cipher suite"));
+    }
+
+    #[test]
+    fn test_expired_certificate_is_refused() {
+        let mut params = rcgen::CertificateParams::new(vec!["old.test".to_string()]).unwrap();
+        let yesterday = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64 - 86400;
+        params.not_after = x509_parser::time::ASN1Time::from_timestamp(yesterday).unwrap().to_datetime();
+        let key = rcgen::KeyPair::generate().unwrap();
+        let dir = std::env::temp_dir();
+        let cert_file = dir.join(format!("hyper-line-{}-expired.pem", std::process::id()));
+        let key_file = dir.join(format!("hyper-line-{}-expired.key", std::process::id()));
+        std::fs::write(&cert_file, params.self_signed(&key).unwrap().pem()).unwrap();
+        std::fs::write(&key_file, key.serialize_pem()).unwrap();
+
+        let mut config = TlsConfig {
+            certificates: vec![CertificateFiles { cert_file: cert_file.clone(), key_file: key_file.clone() }],
+            expiry: ExpiryConfig { check_interval_secs: 0, ..ExpiryConfig::default() },
+            ..TlsConfig::default()
+        };
+        assert!(config.certificates().unwrap().expiry()[0].days_until_expiry < 0);
+        config.expiry.refuse_expired = true;
+        assert!(config.certificates().unwrap_err().to_string().contains("expired certificates"));
+        std::fs::remove_file(cert_file).unwrap();
+        std::fs::remove_file(key_file).unwrap();
+    }
+
+    #[test]
+    fn test_client_auth_needs_ca() {
+        let mut client_auth = ClientAuthConfig {
+            mode: ClientAuthMode::Required,
+            ..ClientAuthConfig::default()
+        };
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        assert!(client_auth.verifier(provider.clone()).is_err());
+
+        let mut params = rcgen::CertificateParams::new(vec![]).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&rcgen::KeyPair::generate().unwrap()).unwrap();
+        let ca_file = std::env::temp_dir().join(format!("hyper-line-{}-client-ca.pem", std::process::id()));
+        std::fs::write(&ca_file, ca.pem()).unwrap();
+        client_auth.ca_file = Some(ca_file.clone());
+        let verifier = client_auth.verifier(provider).unwrap();
+        assert!(verifier.client_auth_mandatory());
+        std::fs::remove_file(ca_file).unwrap();
+    }
+}
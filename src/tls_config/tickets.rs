@@ -0,0 +1,176 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use aws_lc_rs::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use aws_lc_rs::hmac;
+use log::{info, warn};
+use rustls::server::ProducesTickets;
+
+const MIN_SECRET_LEN: usize = 32;
+
+/// Session ticket encryption shared by every instance reading the same secret file. The
+/// ticket key of each rotation period is derived from the secret, so instances with the same
+/// file and a synchronized clock accept each other's tickets. Tickets of the previous period
+/// are still accepted; the file is read again at every rotation.
+pub struct SharedTicketer {
+    secret_file: PathBuf,
+    rotation_secs: u64,
+    keys: RwLock<PeriodKeys>,
+}
+
+struct PeriodKeys {
+    period: u64,
+    secret: Vec<u8>,
+    current: LessSafeKey,
+    previous: LessSafeKey,
+}
+
+impl fmt::Debug for SharedTicketer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedTicketer")
+            .field("secret_file", &self.secret_file)
+            .field("rotation_secs", &self.rotation_secs)
+            .finish()
+    }
+}
+
+impl SharedTicketer {
+    /// Reads the secret, at least 32 bytes of random data, from `secret_file`.
+    pub fn new(secret_file: PathBuf, rotation_secs: u64) -> io::Result<Self> {
+        let rotation_secs = rotation_secs.max(1);
+        let secret = read_secret(&secret_file)?;
+        let keys = PeriodKeys::derive(current_period(rotation_secs), secret);
+        Ok(Self {
+            secret_file,
+            rotation_secs,
+            keys: RwLock::new(keys),
+        })
+    }
+
+    /* rotates when a new period started, keeping the secret if the file became unreadable */
+    fn rotate(&self) {
+        let period = current_period(self.rotation_secs);
+        if self.keys.read().unwrap().period == period {
+            return;
+        }
+        let mut keys = self.keys.write().unwrap();
+        if keys.period == period {
+            return;
+        }
+        let secret = read_secret(&self.secret_file).unwrap_or_else(|e| {
+            warn!("Keeping the previous session ticket secret: {}", e);
+            keys.secret.clone()
+        });
+        *keys = PeriodKeys::derive(period, secret);
+        info!("Session ticket key rotated");
+    }
+}
+
+impl PeriodKeys {
+    fn derive(period: u64, secret: Vec<u8>) -> Self {
+        Self {
+            period,
+            current: period_key(&secret, period),
+            previous: period_key(&secret, period.wrapping_sub(1)),
+            secret,
+        }
+    }
+}
+
+impl ProducesTickets for SharedTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.rotation_secs.min(u32::MAX as u64) as u32
+    }
+
+    /* ticket: period (8 bytes) || nonce || sealed state */
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.rotate();
+        let keys = self.keys.read().unwrap();
+        let mut nonce = [0u8; NONCE_LEN];
+        aws_lc_rs::rand::fill(&mut nonce).ok()?;
+        let mut sealed = plain.to_vec();
+        keys.current
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(keys.period.to_be_bytes()), &mut sealed)
+            .ok()?;
+        Some([&keys.period.to_be_bytes()[..], &nonce, &sealed].concat())
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.rotate();
+        if cipher.len() < 8 + NONCE_LEN {
+            return None;
+        }
+        let (period, rest) = cipher.split_at(8);
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+        let period = u64::from_be_bytes(period.try_into().ok()?);
+        let keys = self.keys.read().unwrap();
+        let key = if period == keys.period {
+            &keys.current
+        } else if period == keys.period.wrapping_sub(1) {
+            &keys.previous
+        } else {
+            return None;
+        };
+        let mut plain = sealed.to_vec();
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let len = key.open_in_place(nonce, Aad::from(period.to_be_bytes()), &mut plain).ok()?.len();
+        plain.truncate(len);
+        Some(plain)
+    }
+}
+
+fn read_secret(path: &PathBuf) -> io::Result<Vec<u8>> {
+    let secret = std::fs::read(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to open {}: {}", path.display(), e)))?;
+    if secret.len() < MIN_SECRET_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("session ticket secret {} is shorter than {} bytes", path.display(), MIN_SECRET_LEN),
+        ));
+    }
+    Ok(secret)
+}
+
+fn current_period(rotation_secs: u64) -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0) / rotation_secs
+}
+
+fn period_key(secret: &[u8], period: u64) -> LessSafeKey {
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, secret), &period.to_be_bytes());
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, tag.as_ref()).expect("HMAC-SHA256 output is an AES-256 key"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_instances_share_tickets() {
+        let file = std::env::temp_dir().join(format!("hyper-line-{}-ticket-secret", std::process::id()));
+        std::fs::write(&file, [7u8; 32]).unwrap();
+        let first = SharedTicketer::new(file.clone(), 3600).unwrap();
+        let second = SharedTicketer::new(file.clone(), 3600).unwrap();
+
+        let ticket = first.encrypt(b"session state").unwrap();
+        assert_eq!(second.decrypt(&ticket).unwrap(), b"session state");
+        let mut tampered = ticket.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(second.decrypt(&tampered).is_none());
+
+        /* a ticket from two periods ago is no longer accepted */
+        let old_period = (current_period(3600) - 2).to_be_bytes();
+        let mut expired = ticket;
+        expired[..8].copy_from_slice(&old_period);
+        assert!(second.decrypt(&expired).is_none());
+
+        std::fs::write(&file, [7u8; 16]).unwrap();
+        assert!(SharedTicketer::new(file.clone(), 3600).is_err());
+        std::fs::remove_file(file).unwrap();
+    }
+}